@@ -0,0 +1,186 @@
+//! Hard-sphere collisions between bodies with a radius.
+//!
+//! Collisions are resolved after the bodies were gathered, so every process handles the
+//! same collisions on its copy of all bodies in the same order.
+
+use super::Body;
+
+use clap::ValueEnum;
+use std::collections::HashSet;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum CollisionMode {
+    /// Bodies pass through each other
+    None,
+    /// Overlapping bodies merge into one, conserving mass and momentum
+    Merge,
+    /// Overlapping bodies bounce off each other elastically
+    Bounce,
+}
+
+/// Pairs of indices of overlapping massive bodies, found by sweeping along the x-axis.
+///
+/// * `bodies`: All bodies of the system.
+fn overlapping_pairs(bodies: &[Body]) -> Vec<(usize, usize)> {
+    let mut order: Vec<usize> = (0..bodies.len())
+        .filter(|&i| bodies[i].mass > 0f64 && bodies[i].radius > 0f64)
+        .collect();
+    order.sort_by(|&a, &b| {
+        let start = |i: usize| bodies[i].position[0] - bodies[i].radius;
+        start(a)
+            .total_cmp(&start(b))
+            .then(bodies[a].id.cmp(&bodies[b].id))
+    });
+
+    let mut pairs = Vec::new();
+    for (k, &i) in order.iter().enumerate() {
+        let end = bodies[i].position[0] + bodies[i].radius;
+        for &j in order[k + 1..].iter() {
+            if bodies[j].position[0] - bodies[j].radius > end {
+                break;
+            }
+
+            let dx = bodies[j].position[0] - bodies[i].position[0];
+            let dy = bodies[j].position[1] - bodies[i].position[1];
+            let reach = bodies[i].radius + bodies[j].radius;
+            if dx * dx + dy * dy < reach * reach {
+                pairs.push((i, j));
+            }
+        }
+    }
+    pairs
+}
+
+/// Resolve an elastic collision of two bodies along the line of their centers,
+/// conserving momentum and kinetic energy. Bodies already moving apart are left alone.
+///
+/// * `a`: First body.
+/// * `b`: Second body.
+pub(crate) fn bounce(a: &mut Body, b: &mut Body) {
+    let dx = b.position[0] - a.position[0];
+    let dy = b.position[1] - a.position[1];
+    let distance = (dx * dx + dy * dy).sqrt();
+    if distance == 0f64 {
+        return;
+    }
+    let normal = [dx / distance, dy / distance];
+
+    // speed with which the bodies approach each other along the normal
+    let approach =
+        (a.velocity[0] - b.velocity[0]) * normal[0] + (a.velocity[1] - b.velocity[1]) * normal[1];
+    if approach <= 0f64 {
+        return;
+    }
+
+    let total_mass = a.mass + b.mass;
+    let a_change = 2f64 * b.mass / total_mass * approach;
+    let b_change = 2f64 * a.mass / total_mass * approach;
+    for (dim, n) in normal.iter().enumerate() {
+        a.velocity[dim] -= a_change * n;
+        b.velocity[dim] += b_change * n;
+    }
+}
+
+/// Merge body `b` into body `a`, conserving mass, momentum and the covered area.
+/// `b` stays in place as a massless body, to be treated like padding from now on.
+///
+/// * `a`: Body that remains.
+/// * `b`: Body that is absorbed.
+fn merge(a: &mut Body, b: &mut Body) {
+    let total_mass = a.mass + b.mass;
+    for dim in 0..2 {
+        a.position[dim] = (a.position[dim] * a.mass + b.position[dim] * b.mass) / total_mass;
+        a.velocity[dim] = (a.velocity[dim] * a.mass + b.velocity[dim] * b.mass) / total_mass;
+    }
+    a.radius = (a.radius * a.radius + b.radius * b.radius).sqrt();
+    a.mass = total_mass;
+
+    b.mass = 0f64;
+    b.radius = 0f64;
+    b.velocity = [0f64; 2];
+}
+
+/// Find and resolve all collisions between the given bodies.
+///
+/// Returns the number of resolved collisions.
+///
+/// * `bodies`: All bodies of the system.
+/// * `mode`: How colliding bodies are treated.
+/// * `removed`: Ids of the bodies removed from the simulation, the ones absorbed by
+///   merges are added.
+pub(crate) fn resolve_collisions(
+    bodies: &mut [Body],
+    mode: CollisionMode,
+    removed: &mut HashSet<usize>,
+) -> usize {
+    if mode == CollisionMode::None {
+        return 0;
+    }
+
+    let mut n_collisions = 0;
+    for (i, j) in overlapping_pairs(bodies) {
+        // a body may already have been absorbed by an earlier merge
+        if bodies[i].mass == 0f64 || bodies[j].mass == 0f64 {
+            continue;
+        }
+
+        let (low, high) = (i.min(j), i.max(j));
+        let (head, tail) = bodies.split_at_mut(high);
+        let (first, second) = (&mut head[low], &mut tail[0]);
+        match mode {
+            CollisionMode::Bounce => bounce(first, second),
+            CollisionMode::Merge => {
+                // the body with the lower id survives
+                let (survivor, absorbed) = if first.id < second.id {
+                    (first, second)
+                } else {
+                    (second, first)
+                };
+                merge(survivor, absorbed);
+                removed.insert(absorbed.id);
+            }
+            CollisionMode::None => unreachable!(),
+        }
+        n_collisions += 1;
+    }
+    n_collisions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ball(id: usize, x: f64, vx: f64) -> Body {
+        Body {
+            id,
+            mass: 2f64,
+            position: [x, 0f64],
+            velocity: [vx, 0f64],
+            radius: 0.5,
+            ..Body::default()
+        }
+    }
+
+    #[test]
+    fn head_on_elastic_collision_swaps_velocities() {
+        let mut bodies = [ball(0, -0.4, 3f64), ball(1, 0.4, -1f64)];
+        let n = resolve_collisions(&mut bodies, CollisionMode::Bounce, &mut HashSet::new());
+        assert_eq!(n, 1);
+        assert_eq!(bodies[0].velocity, [-1f64, 0f64]);
+        assert_eq!(bodies[1].velocity, [3f64, 0f64]);
+    }
+
+    #[test]
+    fn absorbed_body_is_removed() {
+        let mut bodies = [
+            ball(1, -0.4, 3f64),
+            ball(0, 0.4, -1f64),
+            ball(2, 5f64, 0f64),
+        ];
+        let mut removed = HashSet::new();
+        resolve_collisions(&mut bodies, CollisionMode::Merge, &mut removed);
+        assert_eq!(removed, HashSet::from([1]));
+        assert_eq!((bodies[1].mass, bodies[1].velocity), (4f64, [1f64, 0f64]));
+        assert_eq!(bodies[0].mass, 0f64);
+    }
+}
@@ -0,0 +1,560 @@
+//! Cheap global diagnostics of the system, computed on the root from all bodies.
+
+use super::{get_bounds, Body};
+
+use rand::Rng;
+
+/// Mass-weighted mean position and velocity of the given bodies.
+///
+/// Returns `None` if the bodies have no mass at all.
+///
+/// * `bodies`: Bodies to compute the center of mass of.
+pub(crate) fn center_of_mass(bodies: &[Body]) -> Option<([f64; 2], [f64; 2])> {
+    let total_mass: f64 = bodies.iter().map(|b| b.mass).sum();
+    if total_mass <= 0f64 {
+        return None;
+    }
+
+    let mut position = [0f64; 2];
+    let mut velocity = [0f64; 2];
+    for b in bodies {
+        for dim in 0..2 {
+            position[dim] += b.position[dim] * b.mass / total_mass;
+            velocity[dim] += b.velocity[dim] * b.mass / total_mass;
+        }
+    }
+    Some((position, velocity))
+}
+
+/// Total angular momentum `L_z = sum(m (x v_y - y v_x))` of the bodies about the origin,
+/// conserved in a closed system.
+///
+/// * `bodies`: Bodies to sum the angular momentum of.
+pub(crate) fn angular_momentum(bodies: &[Body]) -> f64 {
+    bodies
+        .iter()
+        .map(|b| b.mass * (b.position[0] * b.velocity[1] - b.position[1] * b.velocity[0]))
+        .sum()
+}
+
+/// Distance from the center of mass within which half of the total mass lies.
+///
+/// Returns `None` if the bodies have no mass at all.
+///
+/// * `bodies`: Bodies to compute the half-mass radius of.
+pub(crate) fn half_mass_radius(bodies: &[Body]) -> Option<f64> {
+    lagrangian_radii(bodies, &[0.5]).map(|radii| radii[0])
+}
+
+/// Distances from the center of mass within which the given fractions of the total mass
+/// lie, the Lagrangian radii.
+///
+/// Returns `None` if the bodies have no mass at all.
+///
+/// * `bodies`: Bodies to compute the radii of.
+/// * `fractions`: Fractions of the total mass in (0, 1].
+pub(crate) fn lagrangian_radii(bodies: &[Body], fractions: &[f64]) -> Option<Vec<f64>> {
+    let (com, _) = center_of_mass(bodies)?;
+    let total_mass: f64 = bodies.iter().map(|b| b.mass).sum();
+
+    let mut by_distance: Vec<(f64, f64)> = bodies
+        .iter()
+        .map(|b| {
+            let distance = f64::hypot(b.position[0] - com[0], b.position[1] - com[1]);
+            (distance, b.mass)
+        })
+        .collect();
+    by_distance.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    // cumulative mass up to and including every body
+    let enclosed: Vec<f64> = by_distance
+        .iter()
+        .scan(0f64, |sum, (_, mass)| {
+            *sum += mass;
+            Some(*sum)
+        })
+        .collect();
+    let radii = fractions
+        .iter()
+        .map(|fraction| {
+            // rounding may leave the last sum just below the total mass
+            let i = enclosed.partition_point(|&m| m < fraction * total_mass);
+            by_distance[i.min(by_distance.len() - 1)].0
+        })
+        .collect();
+    Some(radii)
+}
+
+/// Time scales of the two-body relaxation of a system.
+#[derive(Debug)]
+pub(crate) struct RelaxationTime {
+    /// Time a typical body needs to cross the half-mass radius
+    pub(crate) crossing: f64,
+    /// Time after which encounters have changed the velocities as much as they are large,
+    /// `N / (8 ln N)` crossing times
+    pub(crate) relaxation: f64,
+}
+
+/// Estimate of the two-body relaxation time from the half-mass radius and the
+/// mass-weighted velocity dispersion about the center of mass.
+///
+/// Returns `None` if the bodies have no mass, fewer than two bodies have mass or they do
+/// not move relative to each other.
+///
+/// * `bodies`: Bodies to estimate the relaxation time of.
+pub(crate) fn relaxation_time(bodies: &[Body]) -> Option<RelaxationTime> {
+    let (_, com_velocity) = center_of_mass(bodies)?;
+    let radius = half_mass_radius(bodies)?;
+    let total_mass: f64 = bodies.iter().map(|b| b.mass).sum();
+    let n = bodies.iter().filter(|b| b.mass > 0f64).count() as f64;
+
+    let dispersion = (bodies
+        .iter()
+        .map(|b| {
+            let v = [
+                b.velocity[0] - com_velocity[0],
+                b.velocity[1] - com_velocity[1],
+            ];
+            b.mass * (v[0] * v[0] + v[1] * v[1])
+        })
+        .sum::<f64>()
+        / total_mass)
+        .sqrt();
+    if n < 2f64 || dispersion.is_nan() || dispersion <= 0f64 {
+        return None;
+    }
+
+    let crossing = radius / dispersion;
+    Some(RelaxationTime {
+        crossing,
+        relaxation: n / (8f64 * n.ln()) * crossing,
+    })
+}
+
+/// Moment of inertia tensor about the center of mass and its principal axes.
+#[derive(Debug)]
+pub(crate) struct Inertia {
+    /// Tensor `[[I_xx, I_xy], [I_xy, I_yy]]` for rotations in the plane's axes
+    pub(crate) tensor: [[f64; 2]; 2],
+    /// Principal moments, the larger one first
+    pub(crate) moments: [f64; 2],
+    /// Angle of the major axis of the mass distribution to the x-axis in radians
+    pub(crate) major_angle: f64,
+    /// Ratio of the minor to the major axis of the mass distribution, from 0 (a line)
+    /// to 1 (isotropic)
+    pub(crate) axis_ratio: f64,
+}
+
+/// Moment of inertia tensor of the bodies about their center of mass, diagonalized.
+///
+/// The axis lengths of the mass distribution are the square roots of the principal
+/// moments of its second moment tensor `sum(m x x^T)`, whose eigenvalues are those of
+/// the inertia tensor swapped.
+///
+/// Returns `None` if the bodies have no mass at all.
+///
+/// * `bodies`: Bodies to compute the tensor of.
+pub(crate) fn inertia(bodies: &[Body]) -> Option<Inertia> {
+    let (com, _) = center_of_mass(bodies)?;
+
+    let (mut sxx, mut syy, mut sxy) = (0f64, 0f64, 0f64);
+    for b in bodies {
+        let x = b.position[0] - com[0];
+        let y = b.position[1] - com[1];
+        sxx += b.mass * x * x;
+        syy += b.mass * y * y;
+        sxy += b.mass * x * y;
+    }
+
+    // eigenvalues of the symmetric second moment tensor
+    let mean = (sxx + syy) / 2f64;
+    let radius = f64::hypot((sxx - syy) / 2f64, sxy);
+    let (major, minor) = (mean + radius, mean - radius);
+
+    Some(Inertia {
+        tensor: [[syy, -sxy], [-sxy, sxx]],
+        moments: [major, minor],
+        major_angle: 0.5 * f64::atan2(2f64 * sxy, sxx - syy),
+        axis_ratio: if major > 0f64 {
+            (minor.max(0f64) / major).sqrt()
+        } else {
+            1f64
+        },
+    })
+}
+
+/// Surface density in `bins` equally wide rings about the center of mass, from 0 to the
+/// distance of the farthest body.
+///
+/// Returns the ring width and the mass per area of every ring, or `None` if the bodies
+/// have no mass at all.
+///
+/// * `bodies`: Bodies to compute the profile of.
+/// * `bins`: Number of rings.
+pub(crate) fn density_profile(bodies: &[Body], bins: usize) -> Option<(f64, Vec<f64>)> {
+    let (com, _) = center_of_mass(bodies)?;
+    let distances: Vec<f64> = bodies
+        .iter()
+        .map(|b| f64::hypot(b.position[0] - com[0], b.position[1] - com[1]))
+        .collect();
+    let outer = distances.iter().cloned().fold(0f64, f64::max);
+    if outer <= 0f64 {
+        // all mass sits in a single point
+        return None;
+    }
+
+    let width = outer / bins as f64;
+    let mut masses = vec![0f64; bins];
+    for (b, distance) in bodies.iter().zip(&distances) {
+        // the farthest body lies on the outer edge of the last ring
+        let bin = ((distance / width) as usize).min(bins - 1);
+        masses[bin] += b.mass;
+    }
+
+    let densities = masses
+        .iter()
+        .enumerate()
+        .map(|(i, mass)| {
+            let [inner, outer] = [i as f64 * width, (i + 1) as f64 * width];
+            mass / (std::f64::consts::PI * (outer * outer - inner * inner))
+        })
+        .collect();
+    Some((width, densities))
+}
+
+/// Histogram of the distances between pairs of bodies with `bins` equally wide bins
+/// from 0 to the largest possible distance, the diagonal of the bounds.
+///
+/// Returns the bin width and the counts. With `sample`, only that many randomly chosen
+/// pairs are counted instead of all `n (n - 1) / 2`.
+///
+/// * `bodies`: Bodies to compute the distances of.
+/// * `bins`: Number of bins.
+/// * `sample`: Number of random pairs to estimate the histogram from, together with the
+///   source of the random numbers.
+pub(crate) fn pair_distance_histogram(
+    bodies: &[Body],
+    bins: usize,
+    sample: Option<(usize, &mut impl Rng)>,
+) -> (f64, Vec<u64>) {
+    let mut counts = vec![0u64; bins];
+    if bodies.len() < 2 {
+        return (0f64, counts);
+    }
+
+    let positions: Vec<[f64; 2]> = bodies.iter().map(|b| b.position).collect();
+    let bounds = get_bounds(&positions)
+        .unwrap_or_else(|e| panic!("Could not compute bounds of the pair histogram: {}", e));
+    let diagonal = f64::hypot(bounds[0][1] - bounds[0][0], bounds[1][1] - bounds[1][0]);
+    let bin_width = diagonal / bins as f64;
+
+    let mut count = |a: &Body, b: &Body| {
+        let distance = f64::hypot(a.position[0] - b.position[0], a.position[1] - b.position[1]);
+        let bin = if bin_width > 0f64 {
+            ((distance / bin_width) as usize).min(bins - 1)
+        } else {
+            0
+        };
+        counts[bin] += 1;
+    };
+
+    match sample {
+        Some((n_pairs, rng)) => {
+            for _ in 0..n_pairs {
+                let i = rng.gen_range(0..bodies.len());
+                // skipping i makes the pairs uniformly distributed over all distinct pairs
+                let j = (i + rng.gen_range(1..bodies.len())) % bodies.len();
+                count(&bodies[i], &bodies[j]);
+            }
+        }
+        None => {
+            for (i, a) in bodies.iter().enumerate() {
+                for b in &bodies[i + 1..] {
+                    count(a, b);
+                }
+            }
+        }
+    }
+
+    (bin_width, counts)
+}
+
+/// Stable 64-bit FNV-1a hash of the positions of the bodies, each coordinate rounded to
+/// `digits` significant digits so that float noise of different platforms is tolerated.
+///
+/// * `bodies`: Bodies to hash, in a fixed order such as sorted by id.
+/// * `digits`: Number of significant digits the coordinates are rounded to.
+pub(crate) fn position_checksum(bodies: &[Body], digits: usize) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for b in bodies {
+        for x in b.position {
+            // adding zero maps -0.0 to 0.0, which would round to a different string
+            let rounded = format!("{:.*e};", digits.saturating_sub(1), x + 0f64);
+            for byte in rounded.bytes() {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+    }
+    hash
+}
+
+/// Whether any two bodies are closer than `distance` to each other.
+///
+/// Sweeps over the bodies sorted by x, so only pairs less than `distance` apart in x are
+/// compared.
+///
+/// * `bodies`: Bodies to check.
+/// * `distance`: Distance below which a pair counts as close.
+pub(crate) fn any_pair_within(bodies: &[Body], distance: f64) -> bool {
+    let mut positions: Vec<[f64; 2]> = bodies.iter().map(|b| b.position).collect();
+    positions.sort_by(|a, b| a[0].total_cmp(&b[0]));
+
+    for (i, a) in positions.iter().enumerate() {
+        for b in positions[i + 1..]
+            .iter()
+            .take_while(|b| b[0] - a[0] < distance)
+        {
+            if f64::hypot(b[0] - a[0], b[1] - a[1]) < distance {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    /// Probability density of the distance between two uniformly distributed points in the
+    /// unit square.
+    fn unit_square_distance_density(r: f64) -> f64 {
+        use std::f64::consts::PI;
+        if r <= 1f64 {
+            2f64 * r * (PI - 4f64 * r + r * r)
+        } else {
+            let root = (r * r - 1f64).sqrt();
+            2f64 * r * (4f64 * root - (r * r + 2f64 - PI) - 4f64 * (1f64 / r).acos())
+        }
+    }
+
+    /// Uniformly distributed bodies in the unit square, with one in each corner so that
+    /// the diagonal of their bounds is exactly that of the square.
+    fn unit_square_bodies(n: usize, seed: u64) -> Vec<Body> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let corners = [[0f64, 0f64], [1f64, 0f64], [0f64, 1f64], [1f64, 1f64]];
+        let random = (4..n).map(|_| [rng.gen::<f64>(), rng.gen::<f64>()]);
+        corners
+            .into_iter()
+            .chain(random)
+            .enumerate()
+            .map(|(id, position)| Body {
+                id,
+                mass: 1f64,
+                position,
+                ..Body::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn uniform_pair_distances_follow_the_analytic_density() {
+        let bodies = unit_square_bodies(4000, 4);
+        let bins = 8;
+        let mut rng = StdRng::seed_from_u64(5);
+        for sample in [None, Some((200_000, &mut rng))] {
+            let (bin_width, counts) = pair_distance_histogram(&bodies, bins, sample);
+            assert!((bin_width - 2f64.sqrt() / bins as f64).abs() < 1e-12);
+            let total = counts.iter().sum::<u64>() as f64;
+            for (i, count) in counts.iter().enumerate() {
+                // midpoint rule over 1000 steps per bin
+                let steps = 1000;
+                let expected: f64 = (0..steps)
+                    .map(|k| {
+                        let r = (i as f64 + (k as f64 + 0.5) / steps as f64) * bin_width;
+                        unit_square_distance_density(r) * bin_width / steps as f64
+                    })
+                    .sum();
+                let observed = *count as f64 / total;
+                assert!(
+                    (observed - expected).abs() < 0.005,
+                    "bin {}: {} {}",
+                    i,
+                    observed,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn half_mass_radius_of_uniform_disk() {
+        // the 2D analogue of a uniform sphere, half of its mass lies within R / sqrt(2)
+        let radius = 3f64;
+        let mut rng = StdRng::seed_from_u64(8);
+        let bodies: Vec<Body> =
+            std::iter::repeat_with(|| [rng.gen_range(-1f64..1f64), rng.gen_range(-1f64..1f64)])
+                .filter(|p| p[0].hypot(p[1]) <= 1f64)
+                .take(20_000)
+                .enumerate()
+                .map(|(id, p)| Body {
+                    id,
+                    mass: 2f64,
+                    position: [5f64 + radius * p[0], -1f64 + radius * p[1]],
+                    ..Body::default()
+                })
+                .collect();
+        let expected = radius / 2f64.sqrt();
+        let half_mass = half_mass_radius(&bodies).unwrap();
+        assert!(
+            (half_mass - expected).abs() < 0.01 * expected,
+            "{}",
+            half_mass
+        );
+    }
+
+    #[test]
+    fn axis_ratio_of_uniform_ellipse() {
+        // the second moments of a uniform ellipse are a^2 / 4 and b^2 / 4 along its axes,
+        // so the axis ratio is b / a
+        let (a, b) = (4f64, 1f64);
+        let angle = 30f64.to_radians();
+        let mut rng = StdRng::seed_from_u64(11);
+        let bodies: Vec<Body> =
+            std::iter::repeat_with(|| [rng.gen_range(-1f64..1f64), rng.gen_range(-1f64..1f64)])
+                .filter(|p| p[0].hypot(p[1]) <= 1f64)
+                .take(20_000)
+                .enumerate()
+                .map(|(id, p)| {
+                    let (x, y) = (a * p[0], b * p[1]);
+                    Body {
+                        id,
+                        mass: 1.5,
+                        position: [
+                            2f64 + x * angle.cos() - y * angle.sin(),
+                            -3f64 + x * angle.sin() + y * angle.cos(),
+                        ],
+                        ..Body::default()
+                    }
+                })
+                .collect();
+        let inertia = inertia(&bodies).unwrap();
+        assert!(
+            (inertia.axis_ratio - b / a).abs() < 0.01,
+            "{}",
+            inertia.axis_ratio
+        );
+        assert!(
+            (inertia.major_angle - angle).abs() < 0.01,
+            "{}",
+            inertia.major_angle
+        );
+        assert!(inertia.moments[0] >= inertia.moments[1]);
+    }
+
+    #[test]
+    fn uniform_disk_has_a_flat_density_profile() {
+        // the 2D analogue of a uniform sphere, of surface density M / (pi R^2)
+        let (radius, n) = (2f64, 40_000);
+        let mut rng = StdRng::seed_from_u64(15);
+        let bodies: Vec<Body> =
+            std::iter::repeat_with(|| [rng.gen_range(-1f64..1f64), rng.gen_range(-1f64..1f64)])
+                .filter(|p| p[0].hypot(p[1]) <= 1f64)
+                .take(n)
+                .enumerate()
+                .map(|(id, p)| Body {
+                    id,
+                    mass: 0.5,
+                    position: [radius * p[0] - 3f64, radius * p[1]],
+                    ..Body::default()
+                })
+                .collect();
+        let (width, densities) = density_profile(&bodies, 5).unwrap();
+        assert!((width - radius / 5f64).abs() < 0.01, "{}", width);
+        let expected = 0.5 * n as f64 / (std::f64::consts::PI * radius * radius);
+        // the innermost ring holds the fewest bodies, so it is the noisiest
+        for (i, density) in densities.iter().enumerate() {
+            assert!(
+                (density / expected - 1f64).abs() < 0.1,
+                "ring {}: {} {}",
+                i,
+                density,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn relaxation_time_scales_as_n_over_ln_n() {
+        // copies of the bodies with the mass spread over them keep the half-mass radius and
+        // the velocity dispersion, so only the number of bodies changes the estimate
+        let mut bodies = unit_square_bodies(50, 21);
+        let mut rng = StdRng::seed_from_u64(22);
+        for b in bodies.iter_mut() {
+            b.velocity = [rng.gen::<f64>() - 0.5, rng.gen::<f64>() - 0.5];
+        }
+        let relaxation_of = |copies: usize| {
+            let spread: Vec<Body> = (0..copies)
+                .flat_map(|_| bodies.iter())
+                .enumerate()
+                .map(|(id, b)| Body {
+                    id,
+                    mass: b.mass / copies as f64,
+                    ..b.clone()
+                })
+                .collect();
+            relaxation_time(&spread).unwrap()
+        };
+
+        let base = relaxation_of(1);
+        let n = bodies.len() as f64;
+        for copies in [2, 4, 16] {
+            let times = relaxation_of(copies);
+            let m = n * copies as f64;
+            let expected = (m / m.ln()) / (n / n.ln());
+            assert!((times.crossing / base.crossing - 1f64).abs() < 1e-9);
+            let ratio = times.relaxation / base.relaxation;
+            assert!(
+                (ratio / expected - 1f64).abs() < 1e-9,
+                "{} {}",
+                ratio,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn lagrangian_radii_of_uniform_disk() {
+        // bodies are 2D, so the uniform sphere's r ∝ fraction^(1/3) becomes the uniform
+        // disk's r ∝ fraction^(1/2)
+        let radius = 2f64;
+        let mut rng = StdRng::seed_from_u64(9);
+        let bodies: Vec<Body> =
+            std::iter::repeat_with(|| [rng.gen_range(-1f64..1f64), rng.gen_range(-1f64..1f64)])
+                .filter(|p| p[0].hypot(p[1]) <= 1f64)
+                .take(20_000)
+                .enumerate()
+                .map(|(id, p)| Body {
+                    id,
+                    mass: 1f64,
+                    position: [radius * p[0], 3f64 + radius * p[1]],
+                    ..Body::default()
+                })
+                .collect();
+        let fractions = [0.1, 0.25, 0.5, 0.75];
+        let radii = lagrangian_radii(&bodies, &fractions).unwrap();
+        for (fraction, r) in fractions.iter().zip(&radii) {
+            let expected = radius * fraction.sqrt();
+            assert!(
+                (r - expected).abs() < 0.02 * expected,
+                "{}: {}",
+                fraction,
+                r
+            );
+        }
+        assert!(radii.windows(2).all(|w| w[0] < w[1]));
+    }
+}
@@ -0,0 +1,367 @@
+//! Energy diagnostics of the system.
+//!
+//! Every process computes the energy contributions of its own bodies, which are then
+//! summed up over all processes.
+
+use super::diagnostics::center_of_mass;
+use super::tree::{pair_potential, Softening, TreeNode};
+use super::Body;
+use super::{empty_tree, get_bounds};
+
+use mpi::collective::SystemOperation;
+use mpi::topology::SimpleCommunicator;
+use mpi::traits::*;
+
+/// Kinetic and potential energy of the system.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Energy {
+    pub(crate) kinetic: f64,
+    pub(crate) potential: f64,
+}
+
+impl Energy {
+    pub(crate) fn total(&self) -> f64 {
+        self.kinetic + self.potential
+    }
+
+    /// Virial ratio `2K / |U|`, which is 1 for a system in virial equilibrium.
+    pub(crate) fn virial_ratio(&self) -> f64 {
+        2f64 * self.kinetic / self.potential.abs()
+    }
+}
+
+/// Kinetic energy of the given bodies.
+///
+/// * `bodies`: Bodies to sum the kinetic energy of.
+pub(crate) fn kinetic_energy(bodies: &[Body]) -> f64 {
+    bodies
+        .iter()
+        .map(|b| 0.5 * b.mass * (b.velocity[0] * b.velocity[0] + b.velocity[1] * b.velocity[1]))
+        .sum()
+}
+
+/// Exact potential energy of `bodies` in the field of `all_bodies`, summing over all pairs.
+///
+/// Each pair is counted from both sides, so the result is halved.
+///
+/// * `bodies`: Bodies to calculate the potential energy of.
+/// * `all_bodies`: All bodies of the system.
+/// * `softening`: Softening of the force, Newtonian if `None`.
+pub(crate) fn exact_potential_energy(
+    bodies: &[Body],
+    all_bodies: &[Body],
+    softening: Option<&Softening>,
+) -> f64 {
+    let mut potential = 0f64;
+    for b in bodies.iter().filter(|b| b.mass > 0f64) {
+        for other in all_bodies.iter().filter(|o| o.mass > 0f64 && o.id != b.id) {
+            let dx = other.position[0] - b.position[0];
+            let dy = other.position[1] - b.position[1];
+            let distance = (dx * dx + dy * dy).sqrt();
+            potential += pair_potential(b.mass * other.mass, distance, softening);
+        }
+    }
+    0.5 * potential
+}
+
+/// Potential energy of `bodies` approximated with the Barnes-Hut tree.
+///
+/// The absolute value is only approximate, but its drift over time is meaningful.
+///
+/// * `bodies`: Bodies to calculate the potential energy of.
+/// * `root`: Merged tree of all bodies.
+/// * `theta`: Threshold ratio parameter of the tree approximation.
+/// * `softening`: Softening of the force, Newtonian if `None`.
+pub(crate) fn tree_potential_energy(
+    bodies: &[Body],
+    root: &TreeNode,
+    theta: f64,
+    softening: Option<&Softening>,
+) -> f64 {
+    0.5 * bodies
+        .iter()
+        .filter(|b| b.mass > 0f64)
+        .map(|b| root.calculate_potential(b, theta, softening))
+        .sum::<f64>()
+}
+
+/// Total momentum of the whole system, reduced over all processes.
+///
+/// * `world`: MPI communicator
+/// * `local_bodies`: Bodies owned by this process.
+pub(crate) fn system_momentum(world: &SimpleCommunicator, local_bodies: &[Body]) -> [f64; 2] {
+    let mut local = [0f64; 2];
+    for b in local_bodies {
+        local[0] += b.mass * b.velocity[0];
+        local[1] += b.mass * b.velocity[1];
+    }
+    let mut global = [0f64; 2];
+    world.all_reduce_into(&local[..], &mut global[..], SystemOperation::sum());
+    global
+}
+
+/// Energy of the whole system, reduced over all processes.
+///
+/// * `world`: MPI communicator
+/// * `local_bodies`: Bodies owned by this process.
+/// * `all_bodies`: All bodies of the system, used for the exact potential.
+/// * `tree`: Merged tree, used for the approximated potential if given.
+/// * `theta`: Threshold ratio parameter of the tree approximation.
+/// * `softening`: Softening of the force, Newtonian if `None`.
+pub(crate) fn system_energy(
+    world: &SimpleCommunicator,
+    local_bodies: &[Body],
+    all_bodies: &[Body],
+    tree: Option<&TreeNode>,
+    theta: f64,
+    softening: Option<&Softening>,
+) -> Energy {
+    let local = [
+        kinetic_energy(local_bodies),
+        match tree {
+            Some(root) => tree_potential_energy(local_bodies, root, theta, softening),
+            None => exact_potential_energy(local_bodies, all_bodies, softening),
+        },
+    ];
+    let mut global = [0f64; 2];
+    world.all_reduce_into(&local[..], &mut global[..], SystemOperation::sum());
+
+    Energy {
+        kinetic: global[0],
+        potential: global[1],
+    }
+}
+
+/// Specific energy, kinetic plus potential energy per unit mass, of every body.
+///
+/// The kinetic part uses the velocity relative to the center of mass. A body with a
+/// negative specific energy is gravitationally bound to the rest of the system. The
+/// potential is approximated with a tree of `bodies`, massless bodies are treated as
+/// test particles of unit mass.
+///
+/// * `bodies`: All bodies of the system, without padding.
+/// * `theta`: Threshold ratio parameter of the tree approximation.
+/// * `softening`: Softening of the force, Newtonian if `None`.
+pub(crate) fn specific_energies(
+    bodies: &[Body],
+    theta: f64,
+    softening: Option<&Softening>,
+) -> Vec<f64> {
+    let com_velocity = center_of_mass(bodies).map_or([0f64; 2], |(_, v)| v);
+    let positions: Vec<[f64; 2]> = bodies.iter().map(|b| b.position).collect();
+    let Ok(bounds) = get_bounds(&positions) else {
+        return vec![f64::NAN; bodies.len()];
+    };
+
+    let mut root = empty_tree(&bounds, 0f64);
+    for b in bodies.iter().filter(|b| b.mass > 0f64) {
+        root.insert(b);
+    }
+
+    bodies
+        .iter()
+        .map(|b| {
+            let v = [
+                b.velocity[0] - com_velocity[0],
+                b.velocity[1] - com_velocity[1],
+            ];
+            let potential = root.calculate_potential(
+                &Body {
+                    mass: 1f64,
+                    ..b.clone()
+                },
+                theta,
+                softening,
+            );
+            0.5 * (v[0] * v[0] + v[1] * v[1]) + potential
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::SofteningKernel;
+    use crate::G;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Tree of all bodies, fitted to their bounds.
+    fn tree_of(bodies: &[Body]) -> TreeNode {
+        let positions: Vec<[f64; 2]> = bodies.iter().map(|b| b.position).collect();
+        let mut root = empty_tree(&get_bounds(&positions).unwrap(), 0.01);
+        for b in bodies {
+            root.insert(b);
+        }
+        root
+    }
+
+    #[test]
+    fn fast_energy_drift_follows_exact_drift() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut bodies: Vec<Body> = (0..300)
+            .map(|id| Body {
+                id,
+                mass: rng.gen_range(1e8..1e9),
+                position: [rng.gen_range(-1f64..1f64), rng.gen_range(-1f64..1f64)],
+                ..Body::default()
+            })
+            .collect();
+        let (theta, timestep) = (0.5, 0.01);
+        let softening = Some(Softening {
+            kernel: SofteningKernel::Plummer,
+            length: 0.05,
+        });
+        let softening = softening.as_ref();
+        let energies = |bodies: &[Body]| {
+            let kinetic = kinetic_energy(bodies);
+            let exact = kinetic + exact_potential_energy(bodies, bodies, softening);
+            let fast = kinetic + tree_potential_energy(bodies, &tree_of(bodies), theta, softening);
+            [exact, fast]
+        };
+
+        let initial = energies(&bodies);
+        for _ in 0..20 {
+            let root = tree_of(&bodies);
+            for b in bodies.iter_mut() {
+                let f = root.calculate_force(b, theta, softening);
+                b.velocity = crate::calc_velocity(&b.velocity, &f, b.mass, timestep);
+                b.position = crate::calc_position(&b.velocity, &b.position, timestep);
+            }
+        }
+        let last = energies(&bodies);
+
+        let [exact, fast] = [0, 1].map(|i| (last[i] - initial[i]) / initial[i].abs());
+        assert!(exact.abs() > 1e-3, "{}", exact);
+        assert!(
+            (fast - exact).abs() < 0.1 * exact.abs(),
+            "{} vs {}",
+            fast,
+            exact
+        );
+    }
+
+    #[test]
+    fn bound_body_has_negative_and_escaper_positive_energy() {
+        // G M = 1, so the escape speed at distance 1 is sqrt(2)
+        let escape_speed = 2f64.sqrt();
+        let bodies = [
+            (0, 1f64 / G, [0f64, 0f64], 0f64),
+            (1, 0f64, [1f64, 0f64], 0.5 * escape_speed),
+            (2, 0f64, [-1f64, 0f64], 2f64 * escape_speed),
+        ]
+        .map(|(id, mass, position, speed)| Body {
+            id,
+            mass,
+            position,
+            velocity: [0f64, speed],
+            ..Body::default()
+        });
+        let energies = specific_energies(&bodies, 0.5, None);
+        assert!((energies[1] - (0.25 - 1f64)).abs() < 1e-9, "{:?}", energies);
+        assert!((energies[2] - (4f64 - 1f64)).abs() < 1e-9, "{:?}", energies);
+    }
+
+    #[test]
+    fn bound_count_of_marginally_bound_cluster_decreases() {
+        // equal masses in a disk of radius 1 with G M = 1 in total and random speeds just
+        // below the escape speed, so that encounters eject bodies
+        let n = 60;
+        let mut rng = StdRng::seed_from_u64(14);
+        let mut bodies: Vec<Body> =
+            std::iter::repeat_with(|| [rng.gen_range(-1f64..1f64), rng.gen_range(-1f64..1f64)])
+                .filter(|p| p[0].hypot(p[1]) <= 1f64)
+                .take(n)
+                .enumerate()
+                .map(|(id, position)| Body {
+                    id,
+                    mass: 1f64 / G / n as f64,
+                    position,
+                    ..Body::default()
+                })
+                .collect();
+        for b in bodies.iter_mut() {
+            let angle = rng.gen_range(0f64..std::f64::consts::TAU);
+            let speed = rng.gen_range(1f64..1.6);
+            b.velocity = [speed * angle.cos(), speed * angle.sin()];
+        }
+        let softening = Softening {
+            kernel: SofteningKernel::Plummer,
+            length: 0.05,
+        };
+        let n_bound = |bodies: &[Body]| {
+            specific_energies(bodies, 0.5, Some(&softening))
+                .into_iter()
+                .filter(|&e| e < 0f64)
+                .count()
+        };
+
+        let mut counts = vec![n_bound(&bodies)];
+        for step in 1..=2000 {
+            let root = tree_of(&bodies);
+            for b in bodies.iter_mut() {
+                let f = root.calculate_force(b, 0.5, Some(&softening));
+                b.velocity = crate::calc_velocity(&b.velocity, &f, b.mass, 0.002);
+                b.position = crate::calc_position(&b.velocity, &b.position, 0.002);
+            }
+            if step % 200 == 0 {
+                counts.push(n_bound(&bodies));
+            }
+        }
+        // encounters also exchange energy between bound bodies, so the count fluctuates
+        // while the cluster dissolves
+        assert_eq!(counts[0], n - 1, "{:?}", counts);
+        assert!(counts.iter().all(|&c| c <= counts[0]), "{:?}", counts);
+        assert!(counts[counts.len() - 1] < counts[0] - 10, "{:?}", counts);
+    }
+
+    #[test]
+    fn softened_potential_conserves_the_energy_of_softened_orbits() {
+        for kernel in [SofteningKernel::Plummer, SofteningKernel::Spline] {
+            let softening = Softening {
+                kernel,
+                length: 0.5,
+            };
+            // an eccentric orbit of G * m = 1 whose pericenter lies within the softening
+            let mut bodies = [(0, -0.5, -0.1), (1, 0.5, 0.1)].map(|(id, x, v)| Body {
+                id,
+                mass: 1f64 / G,
+                position: [x, 0f64],
+                velocity: [0f64, v],
+                ..Body::default()
+            });
+            let energy = |bodies: &[Body], softening: Option<&Softening>| {
+                kinetic_energy(bodies) + exact_potential_energy(bodies, bodies, softening)
+            };
+            let initial = [energy(&bodies, Some(&softening)), energy(&bodies, None)];
+            let mut max_drift = [0f64; 2];
+            for _ in 0..4000 {
+                let root = tree_of(&bodies);
+                let forces: Vec<[f64; 2]> = bodies
+                    .iter()
+                    .map(|b| root.calculate_force(b, 0.5, Some(&softening)))
+                    .collect();
+                for (b, f) in bodies.iter_mut().zip(&forces) {
+                    b.velocity = crate::calc_velocity(&b.velocity, f, b.mass, 0.001);
+                    b.position = crate::calc_position(&b.velocity, &b.position, 0.001);
+                }
+                let current = [energy(&bodies, Some(&softening)), energy(&bodies, None)];
+                for i in 0..2 {
+                    let drift = ((current[i] - initial[i]) / initial[i]).abs();
+                    max_drift[i] = max_drift[i].max(drift);
+                }
+            }
+            // the bare potential misses the weakened attraction within the softening length
+            let [softened, bare] = max_drift;
+            assert!(softened < 0.05, "{:?}: {}", kernel, softened);
+            assert!(
+                bare > 100f64 * softened,
+                "{:?}: {} {}",
+                kernel,
+                softened,
+                bare
+            );
+        }
+    }
+}
@@ -0,0 +1,371 @@
+//! Small expression language for generating initial conditions.
+//!
+//! A program is a list of assignments separated by `;`, e.g.
+//! `r = uniform(0, P); theta = uniform(0, 2pi); x = r * cos(theta); y = r * sin(theta)`.
+//! It is evaluated once per body, every assignment may use the inputs given by the caller
+//! (e.g. the body index `i`) and all variables assigned before it.
+//!
+//! Expressions support `+ - * / ^`, parentheses, the constant `pi` and a number directly
+//! followed by a name or parenthesis as a product (`2pi`). The functions are
+//! `uniform(min, max)`, `normal(mean, sigma)`, `sin`, `cos`, `tan`, `atan2(y, x)`, `sqrt`,
+//! `exp`, `ln`, `abs`, `min(a, b)` and `max(a, b)`.
+
+use rand::Rng;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(usize, usize),
+    Symbol(char),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Function {
+    Uniform,
+    Normal,
+    Sin,
+    Cos,
+    Tan,
+    Atan2,
+    Sqrt,
+    Exp,
+    Ln,
+    Abs,
+    Min,
+    Max,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "uniform" => Function::Uniform,
+            "normal" => Function::Normal,
+            "sin" => Function::Sin,
+            "cos" => Function::Cos,
+            "tan" => Function::Tan,
+            "atan2" => Function::Atan2,
+            "sqrt" => Function::Sqrt,
+            "exp" => Function::Exp,
+            "ln" => Function::Ln,
+            "abs" => Function::Abs,
+            "min" => Function::Min,
+            "max" => Function::Max,
+            _ => return None,
+        })
+    }
+
+    fn arity(self) -> usize {
+        match self {
+            Function::Uniform
+            | Function::Normal
+            | Function::Atan2
+            | Function::Min
+            | Function::Max => 2,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Expr {
+    Number(f64),
+    Variable(usize),
+    Negate(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+impl Expr {
+    fn evaluate(&self, values: &[f64], rng: &mut impl Rng) -> f64 {
+        match self {
+            Expr::Number(v) => *v,
+            Expr::Variable(slot) => values[*slot],
+            Expr::Negate(e) => -e.evaluate(values, rng),
+            Expr::Binary(op, l, r) => {
+                let (l, r) = (l.evaluate(values, rng), r.evaluate(values, rng));
+                match op {
+                    '+' => l + r,
+                    '-' => l - r,
+                    '*' => l * r,
+                    '/' => l / r,
+                    _ => l.powf(r),
+                }
+            }
+            Expr::Call(function, args) => {
+                let a: Vec<f64> = args.iter().map(|e| e.evaluate(values, rng)).collect();
+                match function {
+                    Function::Uniform => a[0] + rng.gen::<f64>() * (a[1] - a[0]),
+                    Function::Normal => {
+                        // Box-Muller transform, u1 is in (0, 1] to keep the logarithm finite
+                        let u1 = 1f64 - rng.gen::<f64>();
+                        let u2 = rng.gen::<f64>();
+                        a[0] + a[1]
+                            * (-2f64 * u1.ln()).sqrt()
+                            * (2f64 * std::f64::consts::PI * u2).cos()
+                    }
+                    Function::Sin => a[0].sin(),
+                    Function::Cos => a[0].cos(),
+                    Function::Tan => a[0].tan(),
+                    Function::Atan2 => a[0].atan2(a[1]),
+                    Function::Sqrt => a[0].sqrt(),
+                    Function::Exp => a[0].exp(),
+                    Function::Ln => a[0].ln(),
+                    Function::Abs => a[0].abs(),
+                    Function::Min => a[0].min(a[1]),
+                    Function::Max => a[0].max(a[1]),
+                }
+            }
+        }
+    }
+}
+
+/// Parsed program, with all variables resolved to slots of the value vector.
+#[derive(Debug)]
+pub(crate) struct Program {
+    /// Names of the inputs followed by those of the assigned variables
+    names: Vec<String>,
+    n_inputs: usize,
+    statements: Vec<(usize, Expr)>,
+}
+
+impl Program {
+    /// Parse a program.
+    ///
+    /// * `source`: Text of the program.
+    /// * `inputs`: Names of the values passed to `evaluate`, they cannot be assigned.
+    pub(crate) fn parse(source: &str, inputs: &[&str]) -> Result<Self, String> {
+        let mut parser = Parser {
+            source,
+            tokens: tokenize(source)?,
+            next: 0,
+            names: inputs.iter().map(|n| n.to_string()).collect(),
+        };
+        let mut statements = Vec::new();
+        while parser.peek().is_some() {
+            let name = match parser.advance() {
+                Some((_, Token::Name(start, end))) => &source[start..end],
+                token => return Err(parser.unexpected(token)),
+            };
+            if inputs.contains(&name) || name == "pi" || Function::from_name(name).is_some() {
+                return Err(format!("cannot assign to {}", name));
+            }
+            parser.expect('=')?;
+            let expr = parser.expression()?;
+            let slot = match parser.names.iter().position(|n| n == name) {
+                Some(slot) => slot,
+                None => {
+                    parser.names.push(name.to_string());
+                    parser.names.len() - 1
+                }
+            };
+            statements.push((slot, expr));
+
+            match parser.advance() {
+                None | Some((_, Token::Symbol(';'))) => {}
+                token => return Err(parser.unexpected(token)),
+            }
+        }
+
+        Ok(Program {
+            names: parser.names,
+            n_inputs: inputs.len(),
+            statements,
+        })
+    }
+
+    /// Slot of a variable in the values returned by `evaluate`, if the program assigns it.
+    ///
+    /// * `name`: Name of the variable.
+    pub(crate) fn slot(&self, name: &str) -> Option<usize> {
+        self.names
+            .iter()
+            .skip(self.n_inputs)
+            .position(|n| n == name)
+            .map(|slot| slot + self.n_inputs)
+    }
+
+    /// Run the program and return the values of all inputs and variables by slot.
+    ///
+    /// * `inputs`: Values of the inputs, in the order of their names given to `parse`.
+    /// * `rng`: Source of the random numbers.
+    pub(crate) fn evaluate(&self, inputs: &[f64], rng: &mut impl Rng) -> Vec<f64> {
+        let mut values = inputs.to_vec();
+        values.resize(self.names.len(), 0f64);
+        for (slot, expr) in &self.statements {
+            values[*slot] = expr.evaluate(&values, rng);
+        }
+        values
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, String> {
+    let bytes = source.as_bytes();
+    let is_digit = |i: usize| i < bytes.len() && bytes[i].is_ascii_digit();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let (start, c) = (i, bytes[i]);
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || c == b'.' {
+            while is_digit(i) || bytes.get(i) == Some(&b'.') {
+                i += 1;
+            }
+            // an exponent needs digits, otherwise the e starts a name as in 2exp(1)
+            if matches!(bytes.get(i), Some(b'e' | b'E')) {
+                let sign = matches!(bytes.get(i + 1), Some(b'+' | b'-')) as usize;
+                if is_digit(i + 1 + sign) {
+                    i += 1 + sign;
+                    while is_digit(i) {
+                        i += 1;
+                    }
+                }
+            }
+            let text = &source[start..i];
+            let value = text
+                .parse::<f64>()
+                .map_err(|e| format!("position {}: {}: {}", start + 1, text, e))?;
+            tokens.push((start, Token::Number(value)));
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            tokens.push((start, Token::Name(start, i)));
+        } else if b"+-*/^(),=;".contains(&c) {
+            tokens.push((start, Token::Symbol(c as char)));
+            i += 1;
+        } else {
+            let c = source[start..].chars().next().unwrap();
+            return Err(format!(
+                "position {}: unexpected character {:?}",
+                start + 1,
+                c
+            ));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    names: Vec<String>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<Token> {
+        self.tokens.get(self.next).map(|(_, t)| *t)
+    }
+
+    fn advance(&mut self) -> Option<(usize, Token)> {
+        let token = self.tokens.get(self.next).copied();
+        self.next += 1;
+        token
+    }
+
+    fn unexpected(&self, token: Option<(usize, Token)>) -> String {
+        match token {
+            Some((position, Token::Number(v))) => {
+                format!("position {}: unexpected number {}", position + 1, v)
+            }
+            Some((position, Token::Name(start, end))) => {
+                let name = &self.source[start..end];
+                format!("position {}: unexpected name {}", position + 1, name)
+            }
+            Some((position, Token::Symbol(c))) => {
+                format!("position {}: unexpected '{}'", position + 1, c)
+            }
+            None => "unexpected end of the expression".to_string(),
+        }
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), String> {
+        match self.advance() {
+            Some((_, Token::Symbol(c))) if c == symbol => Ok(()),
+            token => Err(self.unexpected(token)),
+        }
+    }
+
+    /// `term (('+' | '-') term)*`
+    fn expression(&mut self) -> Result<Expr, String> {
+        let mut left = self.term()?;
+        while let Some(Token::Symbol(op @ ('+' | '-'))) = self.peek() {
+            self.next += 1;
+            left = Expr::Binary(op, Box::new(left), Box::new(self.term()?));
+        }
+        Ok(left)
+    }
+
+    /// `unary (('*' | '/') unary)*`, a number followed by a name or '(' is a product too
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        loop {
+            let op = match (self.peek(), &left) {
+                (Some(Token::Symbol(op @ ('*' | '/'))), _) => {
+                    self.next += 1;
+                    op
+                }
+                (Some(Token::Name(..) | Token::Symbol('(')), Expr::Number(_)) => '*',
+                _ => return Ok(left),
+            };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+    }
+
+    /// `'-' unary | primary ('^' unary)?`
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.peek() == Some(Token::Symbol('-')) {
+            self.next += 1;
+            return Ok(Expr::Negate(Box::new(self.unary()?)));
+        }
+        let base = self.primary()?;
+        if self.peek() == Some(Token::Symbol('^')) {
+            self.next += 1;
+            return Ok(Expr::Binary('^', Box::new(base), Box::new(self.unary()?)));
+        }
+        Ok(base)
+    }
+
+    /// `number | name | name '(' arguments ')' | '(' expression ')'`
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.advance() {
+            Some((_, Token::Number(v))) => Ok(Expr::Number(v)),
+            Some((_, Token::Symbol('('))) => {
+                let inner = self.expression()?;
+                self.expect(')')?;
+                Ok(inner)
+            }
+            Some((position, Token::Name(start, end))) => {
+                let name = &self.source[start..end];
+                if let Some(function) = Function::from_name(name) {
+                    self.expect('(')?;
+                    let mut args = vec![self.expression()?];
+                    while self.peek() == Some(Token::Symbol(',')) {
+                        self.next += 1;
+                        args.push(self.expression()?);
+                    }
+                    self.expect(')')?;
+                    if args.len() != function.arity() {
+                        return Err(format!(
+                            "position {}: {} takes {} arguments, got {}",
+                            position + 1,
+                            name,
+                            function.arity(),
+                            args.len()
+                        ));
+                    }
+                    Ok(Expr::Call(function, args))
+                } else if name == "pi" {
+                    Ok(Expr::Number(std::f64::consts::PI))
+                } else {
+                    match self.names.iter().position(|n| n == name) {
+                        Some(slot) => Ok(Expr::Variable(slot)),
+                        None => Err(format!("position {}: unknown name {}", position + 1, name)),
+                    }
+                }
+            }
+            token => Err(self.unexpected(token)),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Spatially uniform external accelerations that vary over time.
+//!
+//! The table is a CSV file with one `time,ax,ay` row per sample, sorted by time. Times
+//! are given in the unit of the step time, accelerations in m/s^2. Empty lines, a header
+//! line and lines starting with `#` are skipped.
+
+use super::input::parse_rows;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+/// Samples of an external acceleration, linearly interpolated in between.
+#[derive(Debug, Default)]
+pub(crate) struct ForceTable {
+    /// Times of the samples in seconds, strictly increasing
+    times: Vec<f64>,
+    accelerations: Vec<[f64; 2]>,
+}
+
+impl ForceTable {
+    /// Parse a table from lines of CSV input.
+    ///
+    /// * `lines`: Lines of the input.
+    /// * `time_scale`: Factor converting the times of the table into seconds.
+    pub(crate) fn parse<I>(lines: I, time_scale: f64) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = std::io::Result<String>>,
+    {
+        let mut table = ForceTable::default();
+
+        for row in parse_rows(lines, |_| Ok(())) {
+            let (line_number, values) = row?;
+            let [time, ax, ay] = values[..] else {
+                return Err(format!(
+                    "line {}: expected 3 values (time,ax,ay), got {}",
+                    line_number,
+                    values.len()
+                ));
+            };
+
+            let time = time * time_scale;
+            if table.times.last().is_some_and(|&last| time <= last) {
+                return Err(format!(
+                    "line {}: times have to be strictly increasing",
+                    line_number
+                ));
+            }
+            table.times.push(time);
+            table.accelerations.push([ax, ay]);
+        }
+
+        if table.times.is_empty() {
+            return Err("the table has no samples".into());
+        }
+        Ok(table)
+    }
+
+    /// Load a table from a CSV file, see `parse`.
+    ///
+    /// * `path`: Path of the CSV file.
+    /// * `time_scale`: Factor converting the times of the table into seconds.
+    pub(crate) fn load(path: &str, time_scale: f64) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("could not open {}: {}", path, e))?;
+        Self::parse(BufReader::new(file).lines(), time_scale)
+    }
+
+    /// Acceleration at the given time, constant before the first and after the last
+    /// sample.
+    ///
+    /// * `time`: Simulated time in seconds.
+    pub(crate) fn acceleration_at(&self, time: f64) -> [f64; 2] {
+        let i = self.times.partition_point(|&t| t <= time);
+        if i == 0 {
+            return self.accelerations[0];
+        }
+        if i == self.times.len() {
+            return self.accelerations[i - 1];
+        }
+
+        let (t0, t1) = (self.times[i - 1], self.times[i]);
+        let (a0, a1) = (self.accelerations[i - 1], self.accelerations[i]);
+        let w = (time - t0) / (t1 - t0);
+        [a0[0] + w * (a1[0] - a0[0]), a0[1] + w * (a1[1] - a0[1])]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::TAU;
+
+    #[test]
+    fn sinusoidal_acceleration_drives_expected_oscillation() {
+        // a = A sin(w t) moves a body with the initial velocity -A / w along
+        // x = -A / w^2 sin(w t)
+        let (amplitude, omega) = (3f64, 2f64);
+        let rows = (0..=1000).map(|i| {
+            let t = i as f64 * 0.01;
+            Ok(format!("{},{},0", t, amplitude * (omega * t).sin()))
+        });
+        let table =
+            ForceTable::parse(std::iter::once(Ok("time,ax,ay".into())).chain(rows), 1f64).unwrap();
+
+        let timestep = 0.001;
+        let (mut position, mut velocity) = ([0f64; 2], [-amplitude / omega, 0f64]);
+        let mut max_distance = 0f64;
+        for step in 0..(2f64 * TAU / omega / timestep) as usize {
+            let a = table.acceleration_at(step as f64 * timestep);
+            velocity = crate::calc_velocity(&velocity, &a, 1f64, timestep);
+            position = crate::calc_position(&velocity, &position, timestep);
+            max_distance = max_distance.max(position[0].abs());
+        }
+        let expected = amplitude / omega.powi(2);
+        assert!(
+            (max_distance - expected).abs() < 0.01 * expected,
+            "{}",
+            max_distance
+        );
+        assert_eq!(position[1], 0f64);
+    }
+}
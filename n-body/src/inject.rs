@@ -0,0 +1,96 @@
+//! Schedule of bodies injected into a running simulation.
+//!
+//! Every data line holds one body as `step,mass,x,y,vx,vy` with an optional `radius`
+//! and integer `tag` column, all in SI units. The body is added to the system after
+//! `step` steps, so it is part of the output frame of that step and feels and exerts
+//! forces from then on. Empty lines, a header line and lines starting with `#` are
+//! skipped.
+
+use super::input::parse_rows;
+use super::Body;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+/// Bodies waiting to be injected, sorted by the step they are due at.
+#[derive(Debug, Default)]
+pub(crate) struct Schedule {
+    entries: std::collections::VecDeque<(usize, Body)>,
+}
+
+impl Schedule {
+    /// Parse a schedule from lines of CSV input.
+    ///
+    /// * `lines`: Lines of the input.
+    pub(crate) fn parse<I>(lines: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = std::io::Result<String>>,
+    {
+        let mut entries = Vec::new();
+
+        for row in parse_rows(lines, |_| Ok(())) {
+            let (line_number, values) = row?;
+            if !(6..=8).contains(&values.len()) {
+                return Err(format!(
+                    "line {}: expected 6 to 8 values (step,mass,x,y,vx,vy[,radius[,tag]]), got {}",
+                    line_number,
+                    values.len()
+                ));
+            }
+            let step = values[0];
+            if step.fract() != 0f64 || step < 0f64 {
+                return Err(format!(
+                    "line {}: step {} is not a non-negative integer",
+                    line_number, step
+                ));
+            }
+            let tag = match values.get(7) {
+                Some(&tag) if tag.fract() == 0f64 && (0f64..=u8::MAX as f64).contains(&tag) => {
+                    tag as u8
+                }
+                Some(tag) => {
+                    return Err(format!(
+                        "line {}: tag {} is not an integer from 0 to 255",
+                        line_number, tag
+                    ))
+                }
+                None => 0,
+            };
+
+            entries.push((
+                step as usize,
+                Body {
+                    // the real id is assigned on injection
+                    id: 0,
+                    mass: values[1],
+                    position: [values[2], values[3]],
+                    velocity: [values[4], values[5]],
+                    radius: values.get(6).cloned().unwrap_or_default(),
+                    tag,
+                },
+            ));
+        }
+
+        // stable, so bodies of the same step keep the order of their lines
+        entries.sort_by_key(|(step, _)| *step);
+        Ok(Schedule {
+            entries: entries.into(),
+        })
+    }
+
+    /// Load a schedule from a CSV file, see `parse`.
+    ///
+    /// * `path`: Path of the CSV file.
+    pub(crate) fn load(path: &str) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("could not open {}: {}", path, e))?;
+        Self::parse(BufReader::new(file).lines())
+    }
+
+    /// Remove and return all bodies due at or before the given step.
+    ///
+    /// * `step`: Number of steps simulated so far.
+    pub(crate) fn take_due(&mut self, step: usize) -> Vec<Body> {
+        let n_due = self.entries.partition_point(|(due, _)| *due <= step);
+        self.entries.drain(..n_due).map(|(_, b)| b).collect()
+    }
+}
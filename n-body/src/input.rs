@@ -0,0 +1,296 @@
+//! Loading of initial conditions from CSV files.
+//!
+//! Every data line holds one body as `mass,x,y,vx,vy` with an optional sixth `radius`
+//! and seventh integer `tag` column. Bodies get their ids in the order of the lines.
+//! Empty lines, a header line and lines starting with `#` are skipped.
+//!
+//! With a maximum number of bodies, a uniform sample of that many lines is kept while
+//! reading (reservoir sampling), so files larger than the memory can be loaded. The
+//! masses of the sample are scaled up to preserve the total mass of the file.
+//!
+//! A comment of the form `# units: NAME` selects the unit system of the file:
+//!
+//! * `si` (default): kilograms, meters and seconds.
+//! * `natural`: units in which G = 1, with kilograms and meters as mass and length units.
+//!   Velocities are rescaled to SI on load and the step time is interpreted in the
+//!   natural time unit `1 / sqrt(G)` seconds.
+
+use super::Body;
+use super::G;
+
+use rand::Rng;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+/// Unit system of an input file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Units {
+    #[default]
+    Si,
+    Natural,
+}
+
+impl Units {
+    /// Factor converting a time span of this unit system into seconds.
+    pub(crate) fn time_scale(&self) -> f64 {
+        match self {
+            Units::Si => 1f64,
+            Units::Natural => 1f64 / G.sqrt(),
+        }
+    }
+}
+
+/// Parse the value of a `# units:` directive.
+///
+/// * `name`: Name of the unit system.
+fn parse_units(name: &str) -> Result<Units, String> {
+    match name.to_lowercase().as_str() {
+        "si" => Ok(Units::Si),
+        "natural" => Ok(Units::Natural),
+        _ => Err(format!("unknown unit system '{}'", name)),
+    }
+}
+
+/// Parse the data rows of CSV input into their values, together with their line numbers.
+///
+/// Empty lines are skipped, and so is the first remaining line if it is not numeric, as
+/// it is a header. The text after the `#` of every comment line is passed to `comment`.
+/// Rows are parsed as they are consumed, so the input does not have to fit into memory.
+///
+/// * `lines`: Lines of the input.
+/// * `comment`: Handler of the comments, its errors are returned with the line number.
+pub(crate) fn parse_rows<I>(
+    lines: I,
+    mut comment: impl FnMut(&str) -> Result<(), String>,
+) -> impl Iterator<Item = Result<(usize, Vec<f64>), String>>
+where
+    I: IntoIterator<Item = std::io::Result<String>>,
+{
+    let mut header_allowed = true;
+    lines
+        .into_iter()
+        .zip(1..)
+        .filter_map(move |(line, line_number)| {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e.to_string())),
+            };
+            let line = line.trim();
+            if let Some(text) = line.strip_prefix('#') {
+                let result = comment(text.trim());
+                return result
+                    .err()
+                    .map(|e| Err(format!("line {}: {}", line_number, e)));
+            }
+            if line.is_empty() {
+                return None;
+            }
+
+            let first = std::mem::replace(&mut header_allowed, false);
+            let values = line
+                .split(',')
+                .map(|v| v.trim().parse::<f64>())
+                .collect::<Result<Vec<f64>, _>>();
+            match values {
+                Ok(values) => Some(Ok((line_number, values))),
+                // only the first row may be a header
+                Err(_) if first => None,
+                Err(e) => Some(Err(format!("line {}: {}", line_number, e))),
+            }
+        })
+}
+
+/// Parse bodies from lines of CSV input.
+///
+/// Returns the bodies, already converted to SI units, together with the unit system
+/// of the input.
+///
+/// * `lines`: Lines of the input.
+/// * `max_bodies`: Number of bodies to sample uniformly from all lines, together with
+///   the source of the random numbers. All bodies are kept if there are not more.
+pub(crate) fn parse_csv<I>(
+    lines: I,
+    mut max_bodies: Option<(usize, &mut impl Rng)>,
+) -> Result<(Vec<Body>, Units), String>
+where
+    I: IntoIterator<Item = std::io::Result<String>>,
+{
+    // bodies with the index of their line among all data lines
+    let mut bodies: Vec<(usize, Body)> = Vec::new();
+    let mut n_read = 0usize;
+    let mut total_mass = 0f64;
+    let mut units = Units::default();
+
+    let rows = parse_rows(lines, |comment| {
+        if let Some(name) = comment.strip_prefix("units:") {
+            units = parse_units(name.trim())?;
+        }
+        Ok(())
+    });
+    for row in rows {
+        let (line_number, values) = row?;
+        if !(5..=7).contains(&values.len()) {
+            return Err(format!(
+                "line {}: expected 5 to 7 values (mass,x,y,vx,vy[,radius[,tag]]), got {}",
+                line_number,
+                values.len()
+            ));
+        }
+        let tag = match values.get(6) {
+            Some(&tag) if tag.fract() == 0f64 && (0f64..=u8::MAX as f64).contains(&tag) => {
+                tag as u8
+            }
+            Some(tag) => {
+                return Err(format!(
+                    "line {}: tag {} is not an integer from 0 to 255",
+                    line_number, tag
+                ))
+            }
+            None => 0,
+        };
+
+        let body = Body {
+            // the final ids are assigned once all lines are read
+            id: 0,
+            mass: values[0],
+            position: [values[1], values[2]],
+            velocity: [values[3], values[4]],
+            radius: values.get(5).cloned().unwrap_or_default(),
+            tag,
+        };
+        total_mass += body.mass;
+        match max_bodies.as_mut() {
+            // algorithm R: line k replaces a random sample with probability max / (k + 1)
+            Some((max, rng)) if bodies.len() >= *max => {
+                let j = rng.gen_range(0..=n_read);
+                if j < *max {
+                    bodies[j] = (n_read, body);
+                }
+            }
+            _ => bodies.push((n_read, body)),
+        }
+        n_read += 1;
+    }
+
+    bodies.sort_by_key(|(index, _)| *index);
+    let kept_mass: f64 = bodies.iter().map(|(_, b)| b.mass).sum();
+    let mass_scale = if bodies.len() < n_read && kept_mass > 0f64 {
+        total_mass / kept_mass
+    } else {
+        1f64
+    };
+    let mut bodies: Vec<Body> = bodies.into_iter().map(|(_, b)| b).collect();
+    for (id, b) in bodies.iter_mut().enumerate() {
+        b.id = id;
+        b.mass *= mass_scale;
+    }
+
+    // lengths are in meters in both systems, so only velocities need rescaling
+    let velocity_scale = 1f64 / units.time_scale();
+    for b in bodies.iter_mut() {
+        b.velocity = [
+            b.velocity[0] * velocity_scale,
+            b.velocity[1] * velocity_scale,
+        ];
+    }
+
+    Ok((bodies, units))
+}
+
+/// Load bodies from a CSV file, see `parse_csv`.
+///
+/// * `path`: Path of the CSV file.
+/// * `max_bodies`: Number of bodies to sample from the file, with the source of the
+///   random numbers.
+pub(crate) fn load_csv(
+    path: &str,
+    max_bodies: Option<(usize, &mut impl Rng)>,
+) -> Result<(Vec<Body>, Units), String> {
+    let file = File::open(path).map_err(|e| format!("could not open {}: {}", path, e))?;
+    parse_csv(BufReader::new(file).lines(), max_bodies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse CSV text, keeping all bodies.
+    fn parse(text: &str) -> Result<(Vec<Body>, Units), String> {
+        let lines = text.lines().map(|l| Ok(l.to_string()));
+        parse_csv(lines, None::<(usize, &mut rand::rngs::ThreadRng)>)
+    }
+
+    #[test]
+    fn commented_csv_loads_every_body() {
+        let text = concat!(
+            "# generated by hand\n",
+            "mass,x,y,vx,vy\n",
+            "1,0,0,0,0\n",
+            "\n",
+            "# a comment\n",
+            "2,1,1,0.5,0\n",
+            "3,2,2,0,0\n",
+        );
+        let (bodies, units) = parse(text).unwrap();
+        assert_eq!(units, Units::Si);
+        assert_eq!(bodies.len(), 3);
+        assert_eq!(bodies.iter().map(|b| b.id).collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!(bodies[1].mass, 2f64);
+        assert_eq!(bodies[1].velocity, [0.5, 0f64]);
+    }
+
+    #[test]
+    fn units_directive_rescales_velocities() {
+        let text = "# units: natural\n1,0,0,2,0\n";
+        let (bodies, units) = parse(text).unwrap();
+        assert_eq!(units, Units::Natural);
+        assert_eq!(bodies[0].velocity, [2f64 * G.sqrt(), 0f64]);
+        assert!(parse("# units: furlongs\n").is_err());
+    }
+
+    #[test]
+    fn only_one_header_line_is_skipped() {
+        let (bodies, _) = parse("# comment\nmass,x,y,vx,vy\n\n1,0,0,0,0\n").unwrap();
+        assert_eq!(bodies.len(), 1);
+        let error = parse("mass,x,y,vx,vy\nkg,m,m,m/s,m/s\n1,0,0,0,0\n").unwrap_err();
+        assert!(error.starts_with("line 2:"), "{}", error);
+        let error = parse("1,0,0,0,0\n# units: furlongs\n").unwrap_err();
+        assert!(error.starts_with("line 2:"), "{}", error);
+    }
+
+    #[test]
+    fn reservoir_sample_is_uniform_over_the_stream() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        // line i holds a body at x = i, so the sample tells which lines were kept
+        let (n_lines, max) = (1000, 100);
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut per_decile = [0usize; 10];
+        for _ in 0..500 {
+            let lines = (0..n_lines).map(|i| Ok(format!("1,{},0,0,0", i)));
+            let (bodies, _) = parse_csv(lines, Some((max, &mut rng))).unwrap();
+            assert_eq!(bodies.len(), max);
+            assert_eq!(
+                bodies.iter().map(|b| b.id).collect::<Vec<_>>(),
+                (0..max).collect::<Vec<_>>()
+            );
+            // the sample keeps the total mass and the order of the lines
+            assert!((bodies.iter().map(|b| b.mass).sum::<f64>() - n_lines as f64).abs() < 1e-9);
+            assert!(bodies
+                .windows(2)
+                .all(|w| w[0].position[0] < w[1].position[0]));
+            for b in &bodies {
+                per_decile[b.position[0] as usize * 10 / n_lines] += 1;
+            }
+        }
+
+        // chi-square with 9 degrees of freedom, 27.9 is its 0.1 % quantile
+        let expected = (500 * max / 10) as f64;
+        let chi_square: f64 = per_decile
+            .iter()
+            .map(|&count| (count as f64 - expected).powi(2) / expected)
+            .sum();
+        assert!(chi_square < 27.9, "{:?}", per_decile);
+    }
+}
@@ -0,0 +1,187 @@
+//! Alternative ways of combining the local trees of all processes into one.
+//!
+//! `allgather` (implemented in `barnes_hut`) sends every tree to every process, which
+//! then merges all of them. `reduce-broadcast` merges the trees pairwise along a
+//! binomial tree towards the root in `ceil(log2(n_proc))` rounds and broadcasts the
+//! single merged tree back, so every process only receives the merged tree once instead
+//! of all `n_proc - 1` trees.
+
+use super::serialization::{deserialize_tree, serialize_tree_into, TreeFormat};
+use super::tree::{NodePool, TreeNode};
+use super::{lap, Timings, ROOT_RANK};
+
+use clap::ValueEnum;
+use mpi::topology::SimpleCommunicator;
+use mpi::traits::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum MergeScheme {
+    Allgather,
+    ReduceBroadcast,
+}
+
+/// Buffers of the merge phase which are kept over all steps, so that they only have to
+/// grow instead of being allocated anew every step.
+#[derive(Debug, Default)]
+pub(crate) struct MergeScratch {
+    /// Serialized local tree
+    pub(crate) serialized: Vec<u8>,
+    /// Serialized lengths of the trees of all processes
+    pub(crate) lengths: Vec<i32>,
+    /// Offsets of the trees of all processes in `all_trees`
+    pub(crate) offsets: Vec<i32>,
+    /// Serialized trees of all processes
+    pub(crate) all_trees: Vec<u8>,
+    /// Children vectors of the trees of earlier steps, filled with `--tree-pool`
+    pub(crate) node_pool: NodePool,
+}
+
+/// Merge the local trees of all processes by reducing them to the root and
+/// broadcasting the result, which then replaces `root` on every process.
+///
+/// The trees are passed along the binomial tree of `reduce_partners`.
+///
+/// * `world`: MPI world.
+/// * `root`: Local tree of this process, replaced by the merged tree.
+/// * `scratch`: Buffers of the merge phase, `scratch.serialized` already holds `root`
+///   serialized in `format`.
+/// * `format`: Serialization format of the exchanged trees.
+/// * `min_size`: Size below which leaves are not split when merging, see
+///   `TreeNode::merge`.
+/// * `timings`: Phase timings of the step the times are added to.
+/// * `lap_time`: Time of the last lap of the phase timings.
+pub(crate) fn reduce_broadcast(
+    world: &SimpleCommunicator,
+    root: &mut TreeNode,
+    scratch: &mut MergeScratch,
+    format: TreeFormat,
+    min_size: f64,
+    timings: &mut Timings,
+    lap_time: &mut f64,
+) {
+    let rank = world.rank() as usize;
+    let n_proc = world.size() as usize;
+
+    // scratch.serialized is only up to date until the first tree is merged into root
+    let mut serialized_is_current = true;
+
+    let (sources, target) = reduce_partners(rank, n_proc);
+    for source in sources {
+        let (bytes, _) = world.process_at_rank(source as i32).receive_vec::<u8>();
+        timings.data_exchange += lap(lap_time);
+        let tree = deserialize_tree(&bytes, format)
+            .unwrap_or_else(|e| panic!("Could not deserialize the tree of rank {}: {}", source, e));
+        timings.deserialize += lap(lap_time);
+        root.merge(tree, min_size);
+        serialized_is_current = false;
+        timings.merge += lap(lap_time);
+    }
+    if let Some(target) = target {
+        if !serialized_is_current {
+            serialize_tree_into(root, format, &mut scratch.serialized);
+            timings.serialize += lap(lap_time);
+        }
+        world
+            .process_at_rank(target as i32)
+            .send(&scratch.serialized[..]);
+        timings.data_exchange += lap(lap_time);
+    }
+
+    let root_proc = world.process_at_rank(ROOT_RANK as i32);
+    if rank == ROOT_RANK && !serialized_is_current {
+        serialize_tree_into(root, format, &mut scratch.serialized);
+        timings.serialize += lap(lap_time);
+    }
+    let mut length = scratch.serialized.len() as u64;
+    root_proc.broadcast_into(&mut length);
+    timings.length_exchange += lap(lap_time);
+
+    // the other processes receive the merged tree into their own serialization buffer
+    scratch.serialized.resize(length as usize, 0u8);
+    root_proc.broadcast_into(&mut scratch.serialized[..]);
+    timings.data_exchange += lap(lap_time);
+
+    if rank != ROOT_RANK {
+        *root = deserialize_tree(&scratch.serialized, format)
+            .unwrap_or_else(|e| panic!("Could not deserialize the merged tree: {}", e));
+        timings.deserialize += lap(lap_time);
+    }
+}
+
+/// Ranks whose trees a process merges into its own, in that order, and the rank it then
+/// sends its tree to, which is `None` for the root.
+///
+/// In round `k`, every process whose rank has bit `k` as its lowest set bit sends its
+/// tree to the rank without that bit, which merges it into its own.
+///
+/// * `rank`: Rank of the process.
+/// * `n_proc`: Number of processes.
+fn reduce_partners(rank: usize, n_proc: usize) -> (Vec<usize>, Option<usize>) {
+    let mut sources = Vec::new();
+    let mut mask = 1;
+    while mask < n_proc {
+        if rank & mask != 0 {
+            return (sources, Some(rank - mask));
+        } else if rank + mask < n_proc {
+            sources.push(rank + mask);
+        }
+        mask <<= 1;
+    }
+    (sources, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Body;
+
+    /// Tree of a rank after merging the trees it receives in `reduce_broadcast`, which are
+    /// taken from `local_trees`.
+    fn reduced_tree(rank: usize, local_trees: &mut Vec<Option<TreeNode>>) -> TreeNode {
+        let (sources, _) = reduce_partners(rank, local_trees.len());
+        let mut root = local_trees[rank].take().unwrap();
+        for source in sources {
+            root.merge(reduced_tree(source, local_trees), 0f64);
+        }
+        root
+    }
+
+    #[test]
+    fn both_schemes_merge_the_same_tree() {
+        let n_proc = 7;
+        let local_trees: Vec<TreeNode> = (0..n_proc)
+            .map(|rank| {
+                let mut tree = TreeNode {
+                    size: 8f64,
+                    ..TreeNode::default()
+                };
+                for id in (rank..100).step_by(n_proc) {
+                    let angle = id as f64 * 2.4;
+                    tree.insert(&Body {
+                        id,
+                        mass: 1f64 + id as f64,
+                        position: [angle.cos() * id as f64 / 30f64, angle.sin()],
+                        ..Body::default()
+                    });
+                }
+                tree
+            })
+            .collect();
+
+        // allgather: every rank merges all other trees into its own in rank order
+        let mut allgather = local_trees[0].clone();
+        for tree in &local_trees[1..] {
+            allgather.merge(tree.clone(), 0f64);
+        }
+        let reduced = reduced_tree(0, &mut local_trees.into_iter().map(Some).collect());
+        assert!((0..n_proc).all(|rank| reduce_partners(rank, n_proc).1.is_some() == (rank != 0)));
+
+        let [allgather, reduced] = [allgather, reduced].map(|mut tree| {
+            tree.recompute_mass_centers();
+            let mut bytes = Vec::new();
+            serialize_tree_into(&tree, TreeFormat::Compact, &mut bytes);
+            bytes
+        });
+        assert_eq!(allgather, reduced);
+    }
+}
@@ -0,0 +1,111 @@
+//! Reproducible random numbers for stochastic forces.
+//!
+//! Instead of keeping RNG state per process, every random number is derived from a hash
+//! of `(seed, step, body id, stream)`. The noise a body receives therefore does not depend
+//! on which process owns it or in which order the bodies are processed.
+//!
+//! Generated initial conditions are drawn in chunks of a fixed size, each from its own
+//! generator seeded with a hash of `(seed, chunk index)`, see `chunk_rng`. They therefore
+//! do not depend on how the chunks are scheduled either.
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+/// Finalizer of the SplitMix64 generator, a cheap and well-mixing 64 bit hash.
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Generator of one chunk of random numbers, independent of all other chunks.
+///
+/// * `seed`: Seed shared by all chunks of one vector.
+/// * `chunk`: Index of the chunk.
+pub(crate) fn chunk_rng(seed: u64, chunk: usize) -> StdRng {
+    StdRng::seed_from_u64(splitmix64(splitmix64(seed) ^ chunk as u64))
+}
+
+/// Uniformly distributed number in (0, 1) for the given key.
+///
+/// * `seed`: Seed of the whole run.
+/// * `step`: Current step.
+/// * `id`: Id of the body.
+/// * `stream`: Index of the random number drawn for this body and step.
+fn counter_uniform(seed: u64, step: u64, id: u64, stream: u64) -> f64 {
+    let hash = splitmix64(splitmix64(splitmix64(splitmix64(seed) ^ step) ^ id) ^ stream);
+    // use the upper 53 bits and shift by half an ulp to exclude 0
+    ((hash >> 11) as f64 + 0.5) / (1u64 << 53) as f64
+}
+
+/// Random velocity kick of a Langevin thermostat for one body and step.
+///
+/// Both components are normally distributed with standard deviation
+/// `sigma * sqrt(timestep)`.
+///
+/// * `seed`: Seed of the whole run.
+/// * `step`: Current step.
+/// * `id`: Id of the body.
+/// * `sigma`: Strength of the noise.
+/// * `timestep`: Size of the timestep.
+pub(crate) fn thermal_kick(
+    seed: u64,
+    step: usize,
+    id: usize,
+    sigma: f64,
+    timestep: f64,
+) -> [f64; 2] {
+    let u1 = counter_uniform(seed, step as u64, id as u64, 0);
+    let u2 = counter_uniform(seed, step as u64, id as u64, 1);
+
+    // Box-Muller transform
+    let radius = (-2f64 * u1.ln()).sqrt();
+    let angle = 2f64 * std::f64::consts::PI * u2;
+    let scale = sigma * timestep.abs().sqrt();
+    [scale * radius * angle.cos(), scale * radius * angle.sin()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Velocities after applying the kicks of `n_steps` steps to `n` bodies split over
+    /// `n_ranks` ranks, processing the ranks in reverse order.
+    fn kicked_velocities(n: usize, n_steps: usize, n_ranks: usize) -> Vec<[f64; 2]> {
+        let mut velocities = vec![[0f64; 2]; n];
+        let per_rank = n.div_ceil(n_ranks);
+        for step in 0..n_steps {
+            for (rank, local) in velocities.chunks_mut(per_rank).enumerate().rev() {
+                for (i, v) in local.iter_mut().enumerate() {
+                    let kick = thermal_kick(42, step, rank * per_rank + i, 0.5, 0.01);
+                    *v = [v[0] + kick[0], v[1] + kick[1]];
+                }
+            }
+        }
+        velocities
+    }
+
+    #[test]
+    fn kicks_do_not_depend_on_rank_count() {
+        let single = kicked_velocities(10, 20, 1);
+        assert_eq!(single, kicked_velocities(10, 20, 3));
+        assert_eq!(single, kicked_velocities(10, 20, 4));
+    }
+
+    #[test]
+    fn kicks_have_expected_spread() {
+        let (sigma, timestep) = (2f64, 0.25);
+        let kicks: Vec<f64> = (0..10000)
+            .flat_map(|id| thermal_kick(7, 3, id, sigma, timestep))
+            .collect();
+        let mean = kicks.iter().sum::<f64>() / kicks.len() as f64;
+        let variance = kicks.iter().map(|k| (k - mean).powi(2)).sum::<f64>() / kicks.len() as f64;
+        assert!(mean.abs() < 0.02, "{}", mean);
+        assert!(
+            (variance - sigma * sigma * timestep).abs() < 0.03,
+            "{}",
+            variance
+        );
+    }
+}
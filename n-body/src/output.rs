@@ -0,0 +1,1463 @@
+//! Trajectory output, written by the root process after each gather.
+//!
+//! The bodies of every frame are sorted by id, independent of how they are currently
+//! partitioned between the processes, so row `i` of a frame always belongs to the same
+//! body (unless `--output-region` drops some of them).
+//!
+//! With `--no-gather`, every rank instead writes the bodies it owns to its own file, see
+//! `per_rank_path`. The frames of all ranks' files with the same step together hold the
+//! full state.
+//!
+//! All formats stream every frame to disk through a buffer of `--output-buffer-bytes`,
+//! so the memory used by the output is bounded by one frame and that buffer, however
+//! many frames are written. Only `--trigger-min-distance` holds back up to
+//! `--trigger-before` more frames, see `TriggerBuffer`.
+//!
+//! Three formats are supported:
+//!
+//! * `csv`: one row per body and frame with the header
+//!   `step,time,id,mass,x,y,vx,vy,radius,tag`, followed by `specific_energy` with
+//!   `--output-energy`. With `--delta-output`, a frame only holds the bodies that moved
+//!   farther than the threshold since their last row, so the full state of a step is
+//!   the last row of every id up to it (see `DeltaFilter`).
+//! * `chunked`: an append-only binary format that never needs to seek or hold more than
+//!   one frame in memory. All numbers are little endian.
+//!
+//! The chunked layout is:
+//!
+//! ```text
+//! header:
+//!   magic      4 bytes   b"NBCH"
+//!   version    u32       currently 1
+//!   n_bodies   u64       number of bodies per frame
+//!   n_fields   u32       number of per-body fields
+//!   fields     n_fields times: u32 name length, followed by the utf-8 name
+//! chunk (one per frame, repeated until EOF):
+//!   length     u64       number of payload bytes following
+//!   step       u64
+//!   time       f64
+//!   bodies     n_bodies times: id as u64, followed by the remaining fields as f64
+//! ```
+//!
+//! `gadget-like` writes every frame to its own file in the classic (format 1) snapshot
+//! layout of GADGET-2, which readers such as yt or pynbody ingest. The file of step 42
+//! of `--output snap.dat` is `snap_000042.dat`, see `frame_path`. All bodies are
+//! particles of type 1, the simulation's z coordinates are 0 and nothing is converted,
+//! so readers have to be given the output's units. Every block is framed like a Fortran
+//! record by its length in bytes as u32 before and after it. All numbers are little
+//! endian and all floats are f32, as in default GADGET builds.
+//!
+//! ```text
+//! HEAD   256 bytes
+//!   npart          6 u32   [0, n, 0, 0, 0, 0]
+//!   massarr        6 f64   all 0, masses are in MASS
+//!   time           f64     simulated time of the frame
+//!   redshift       f64     0
+//!   flag_sfr       i32     0
+//!   flag_feedback  i32     0
+//!   npartTotal     6 u32   same as npart
+//!   flag_cooling   i32     0
+//!   num_files      i32     1
+//!   BoxSize        f64     0
+//!   Omega0         f64     0
+//!   OmegaLambda    f64     0
+//!   HubbleParam    f64     0
+//!   flag_stellarage i32    0
+//!   flag_metals    i32     0
+//!   npartTotalHighWord 6 u32 all 0
+//!   flag_entropy_instead_u i32 0
+//!   padding        60 bytes of 0
+//! POS    n times x, y, 0 as f32
+//! VEL    n times vx, vy, 0 as f32
+//! ID     n times id as u32
+//! MASS   n times mass as f32
+//! ```
+//!
+//! Radius, tag and specific energy have no GADGET block and are not written.
+//!
+//! With `--output-energy`, `specific_energy` is the last field of the chunked format.
+//!
+//! A truncated last chunk (e.g. after a crash) can be detected by its length prefix.
+
+use super::diagnostics::center_of_mass;
+use super::energy::Energy;
+use super::tree::TreeNode;
+use super::Body;
+
+use clap::ValueEnum;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+
+/// Names of the per-body fields of a frame, in the order they are written.
+const FIELDS: [&str; 8] = ["id", "mass", "x", "y", "vx", "vy", "radius", "tag"];
+
+/// Name of the optional field holding the specific energy of every body.
+const ENERGY_FIELD: &str = "specific_energy";
+
+const CHUNKED_MAGIC: &[u8; 4] = b"NBCH";
+const CHUNKED_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum OutputFormat {
+    Csv,
+    Chunked,
+    GadgetLike,
+}
+
+/// Units the output is written in.
+///
+/// The simulation always runs in SI units. Input in other units is converted on load,
+/// see `input::Units`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum OutputUnits {
+    /// Units of the input, in which the step time is given as well
+    Physical,
+    /// SI units the simulation runs in
+    Internal,
+}
+
+impl OutputUnits {
+    /// Factor converting a time span in the units of the input into the output's units.
+    ///
+    /// * `time_scale`: Factor converting a time span of the input's units into seconds.
+    pub(crate) fn time_factor(&self, time_scale: f64) -> f64 {
+        match self {
+            OutputUnits::Physical => 1f64,
+            OutputUnits::Internal => time_scale,
+        }
+    }
+
+    /// Factor converting an SI velocity into the output's units. Lengths are meters in
+    /// all unit systems, so accelerations and energies scale with its square.
+    ///
+    /// * `time_scale`: Factor converting a time span of the input's units into seconds.
+    pub(crate) fn velocity_factor(&self, time_scale: f64) -> f64 {
+        match self {
+            OutputUnits::Physical => time_scale,
+            OutputUnits::Internal => 1f64,
+        }
+    }
+}
+
+/// Coordinate system the written positions and velocities are relative to.
+///
+/// This only affects the output, the simulation always runs in the global frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ReferenceFrame {
+    Global,
+    /// Center of mass of the whole system
+    Com,
+    /// The body with the given id
+    Body(usize),
+}
+
+impl FromStr for ReferenceFrame {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(ReferenceFrame::Global),
+            "com" => Ok(ReferenceFrame::Com),
+            _ => match s.strip_prefix("body:") {
+                Some(id) => id
+                    .parse()
+                    .map(ReferenceFrame::Body)
+                    .map_err(|e| format!("invalid body id '{}': {}", id, e)),
+                None => Err(format!(
+                    "unknown frame '{}', expected global, com or body:ID",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Axis-aligned box of the output, parsed from `xmin,xmax,ymin,ymax`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Region {
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Region {
+    /// Whether the position lies within the box, including its border.
+    ///
+    /// * `position`: Position to check.
+    pub(crate) fn contains(&self, position: &[f64; 2]) -> bool {
+        (self.x[0]..=self.x[1]).contains(&position[0])
+            && (self.y[0]..=self.y[1]).contains(&position[1])
+    }
+
+    /// Drop the bodies of the frame outside the box.
+    ///
+    /// * `frame`: Frame with all bodies.
+    pub(crate) fn filter(&self, frame: BufferedFrame) -> BufferedFrame {
+        let inside: Vec<bool> = frame
+            .bodies
+            .iter()
+            .map(|b| self.contains(&b.position))
+            .collect();
+        BufferedFrame {
+            step: frame.step,
+            time: frame.time,
+            bodies: frame
+                .bodies
+                .into_iter()
+                .zip(&inside)
+                .filter(|(_, &inside)| inside)
+                .map(|(b, _)| b)
+                .collect(),
+            energies: frame.energies.map(|energies| {
+                energies
+                    .into_iter()
+                    .zip(&inside)
+                    .filter(|(_, &inside)| inside)
+                    .map(|(e, _)| e)
+                    .collect()
+            }),
+        }
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|e| format!("invalid region '{}': {}", s, e))?;
+        let [x_min, x_max, y_min, y_max] = values[..] else {
+            return Err(format!(
+                "invalid region '{}', expected xmin,xmax,ymin,ymax",
+                s
+            ));
+        };
+        if x_min > x_max || y_min > y_max {
+            return Err(format!("region '{}' has a minimum above its maximum", s));
+        }
+        Ok(Region {
+            x: [x_min, x_max],
+            y: [y_min, y_max],
+        })
+    }
+}
+
+/// Output stride growing geometrically from `start` to `end` steps over the run, parsed
+/// from `start,end`, so that early frames are written densely and late ones sparsely.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct StrideRamp {
+    start: usize,
+    end: usize,
+}
+
+impl StrideRamp {
+    /// Number of steps from a frame written at the given step to the next one.
+    ///
+    /// * `step`: Step of the written frame.
+    /// * `n_steps`: Number of steps of the whole run.
+    pub(crate) fn stride(&self, step: usize, n_steps: usize) -> usize {
+        let progress = (step as f64 / n_steps.max(1) as f64).min(1f64);
+        let ratio = self.end as f64 / self.start as f64;
+        ((self.start as f64 * ratio.powf(progress)).round() as usize).max(1)
+    }
+}
+
+impl FromStr for StrideRamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<usize>())
+            .collect::<Result<Vec<usize>, _>>()
+            .map_err(|e| format!("invalid stride ramp '{}': {}", s, e))?;
+        let [start, end] = values[..] else {
+            return Err(format!("invalid stride ramp '{}', expected start,end", s));
+        };
+        if start == 0 || end == 0 {
+            return Err(format!("stride ramp '{}' has a stride of 0", s));
+        }
+        Ok(StrideRamp { start, end })
+    }
+}
+
+/// Transform the bodies' positions and velocities into the given reference frame.
+///
+/// * `bodies`: All bodies of the system.
+/// * `frame`: Reference frame to transform into.
+pub(crate) fn to_frame(bodies: &[Body], frame: ReferenceFrame) -> Result<Vec<Body>, String> {
+    let (origin, origin_velocity) = match frame {
+        ReferenceFrame::Global => return Ok(bodies.to_vec()),
+        ReferenceFrame::Com => center_of_mass(bodies)
+            .ok_or("cannot center on the center of mass of a massless system")?,
+        ReferenceFrame::Body(id) => match bodies.iter().find(|b| b.id == id) {
+            Some(b) => (b.position, b.velocity),
+            None => return Err(format!("reference body {} does not exist", id)),
+        },
+    };
+
+    Ok(bodies
+        .iter()
+        .map(|b| Body {
+            position: [b.position[0] - origin[0], b.position[1] - origin[1]],
+            velocity: [
+                b.velocity[0] - origin_velocity[0],
+                b.velocity[1] - origin_velocity[1],
+            ],
+            ..b.clone()
+        })
+        .collect())
+}
+
+/// Writes one frame per call for the whole system.
+pub(crate) trait TrajectoryWriter {
+    /// Write the state of all given bodies at the given step.
+    ///
+    /// * `step`: Number of the step the state belongs to.
+    /// * `time`: Simulated time of the state.
+    /// * `bodies`: All bodies of the system, sorted by id so that rows keep their order
+    ///   however the bodies are distributed, see `partition::real_bodies`.
+    /// * `energies`: Specific energy of every body, if the writer was created with the
+    ///   energy field.
+    fn write_frame(
+        &mut self,
+        step: usize,
+        time: f64,
+        bodies: &[Body],
+        energies: Option<&[f64]>,
+    ) -> std::io::Result<()>;
+
+    /// Flush all buffered frames to the destination.
+    fn flush(&mut self) -> std::io::Result<()>;
+}
+
+/// Flushes the wrapped writer after every `every` frames.
+///
+/// Frames written since the last flush may be lost on a crash, but flushing can be
+/// expensive, e.g. on networked filesystems.
+pub(crate) struct FlushEvery<T: TrajectoryWriter> {
+    inner: T,
+    every: usize,
+    pending: usize,
+}
+
+impl<T: TrajectoryWriter> FlushEvery<T> {
+    pub(crate) fn new(inner: T, every: usize) -> Self {
+        FlushEvery {
+            inner,
+            every,
+            pending: 0,
+        }
+    }
+}
+
+impl<T: TrajectoryWriter> TrajectoryWriter for FlushEvery<T> {
+    fn write_frame(
+        &mut self,
+        step: usize,
+        time: f64,
+        bodies: &[Body],
+        energies: Option<&[f64]>,
+    ) -> std::io::Result<()> {
+        self.inner.write_frame(step, time, bodies, energies)?;
+        self.pending += 1;
+        if self.pending >= self.every {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.pending = 0;
+        self.inner.flush()
+    }
+}
+
+pub(crate) struct CsvWriter<W: Write> {
+    out: W,
+    with_energy: bool,
+}
+
+impl<W: Write> CsvWriter<W> {
+    /// Create the writer and write the header.
+    ///
+    /// * `out`: Destination of the rows.
+    /// * `with_energy`: Whether every row ends with the specific energy of the body.
+    pub(crate) fn new(mut out: W, with_energy: bool) -> std::io::Result<Self> {
+        write!(out, "step,time,{}", FIELDS.join(","))?;
+        if with_energy {
+            write!(out, ",{}", ENERGY_FIELD)?;
+        }
+        writeln!(out)?;
+        Ok(CsvWriter { out, with_energy })
+    }
+}
+
+impl<W: Write> TrajectoryWriter for CsvWriter<W> {
+    fn write_frame(
+        &mut self,
+        step: usize,
+        time: f64,
+        bodies: &[Body],
+        energies: Option<&[f64]>,
+    ) -> std::io::Result<()> {
+        assert_eq!(energies.is_some(), self.with_energy);
+        for (i, b) in bodies.iter().enumerate() {
+            write!(
+                self.out,
+                "{},{},{},{},{},{},{},{},{},{}",
+                step,
+                time,
+                b.id,
+                b.mass,
+                b.position[0],
+                b.position[1],
+                b.velocity[0],
+                b.velocity[1],
+                b.radius,
+                b.tag
+            )?;
+            if let Some(energies) = energies {
+                write!(self.out, ",{}", energies[i])?;
+            }
+            writeln!(self.out)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+pub(crate) struct ChunkedWriter<W: Write> {
+    out: W,
+    n_bodies: usize,
+    with_energy: bool,
+    chunk: Vec<u8>,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    /// Create the writer and write the file header.
+    ///
+    /// * `out`: Destination of the chunks.
+    /// * `n_bodies`: Number of bodies every frame will contain.
+    /// * `with_energy`: Whether the specific energy of every body is written as well.
+    pub(crate) fn new(mut out: W, n_bodies: usize, with_energy: bool) -> std::io::Result<Self> {
+        let fields: Vec<&str> = FIELDS
+            .into_iter()
+            .chain(with_energy.then_some(ENERGY_FIELD))
+            .collect();
+        out.write_all(CHUNKED_MAGIC)?;
+        out.write_all(&CHUNKED_VERSION.to_le_bytes())?;
+        out.write_all(&(n_bodies as u64).to_le_bytes())?;
+        out.write_all(&(fields.len() as u32).to_le_bytes())?;
+        for field in fields {
+            out.write_all(&(field.len() as u32).to_le_bytes())?;
+            out.write_all(field.as_bytes())?;
+        }
+
+        Ok(ChunkedWriter {
+            out,
+            n_bodies,
+            with_energy,
+            chunk: Vec::new(),
+        })
+    }
+}
+
+impl<W: Write> TrajectoryWriter for ChunkedWriter<W> {
+    fn write_frame(
+        &mut self,
+        step: usize,
+        time: f64,
+        bodies: &[Body],
+        energies: Option<&[f64]>,
+    ) -> std::io::Result<()> {
+        assert_eq!(bodies.len(), self.n_bodies);
+        assert_eq!(energies.is_some(), self.with_energy);
+
+        // the chunk buffer is reused between frames
+        self.chunk.clear();
+        self.chunk.extend((step as u64).to_le_bytes());
+        self.chunk.extend(time.to_le_bytes());
+        for (i, b) in bodies.iter().enumerate() {
+            self.chunk.extend((b.id as u64).to_le_bytes());
+            for value in [
+                b.mass,
+                b.position[0],
+                b.position[1],
+                b.velocity[0],
+                b.velocity[1],
+                b.radius,
+                b.tag as f64,
+            ] {
+                self.chunk.extend(value.to_le_bytes());
+            }
+            if let Some(energies) = energies {
+                self.chunk.extend(energies[i].to_le_bytes());
+            }
+        }
+
+        self.out
+            .write_all(&(self.chunk.len() as u64).to_le_bytes())?;
+        self.out.write_all(&self.chunk)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Writes every frame as its own GADGET format 1 snapshot file, see the module docs.
+pub(crate) struct GadgetWriter {
+    path: String,
+    buffer_bytes: usize,
+    block: Vec<u8>,
+}
+
+impl GadgetWriter {
+    /// * `path`: Path of the output, the step of every frame is appended to its stem.
+    /// * `buffer_bytes`: Capacity of the write buffer of every file.
+    pub(crate) fn new(path: &str, buffer_bytes: usize) -> Self {
+        GadgetWriter {
+            path: path.to_string(),
+            buffer_bytes,
+            block: Vec::new(),
+        }
+    }
+
+    /// Write the block buffer framed by its length.
+    fn write_block(&mut self, out: &mut impl Write) -> std::io::Result<()> {
+        let length = (self.block.len() as u32).to_le_bytes();
+        out.write_all(&length)?;
+        out.write_all(&self.block)?;
+        out.write_all(&length)?;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+impl TrajectoryWriter for GadgetWriter {
+    fn write_frame(
+        &mut self,
+        step: usize,
+        time: f64,
+        bodies: &[Body],
+        _energies: Option<&[f64]>,
+    ) -> std::io::Result<()> {
+        if bodies.len() > u32::MAX as usize || bodies.iter().any(|b| b.id > u32::MAX as usize) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "GADGET snapshots hold at most 2^32 bodies with 32 bit ids",
+            ));
+        }
+        let file = File::create(frame_path(&self.path, step))?;
+        let mut out = BufWriter::with_capacity(self.buffer_bytes, file);
+
+        let n = bodies.len() as u32;
+        let npart = [0, n, 0, 0, 0, 0];
+        for count in npart {
+            self.block.extend(count.to_le_bytes());
+        }
+        // massarr, time and redshift
+        self.block.extend([0u8; 6 * 8]);
+        self.block.extend(time.to_le_bytes());
+        self.block.extend(0f64.to_le_bytes());
+        // flag_sfr, flag_feedback
+        self.block.extend([0u8; 2 * 4]);
+        for count in npart {
+            self.block.extend(count.to_le_bytes());
+        }
+        // flag_cooling, num_files
+        self.block.extend(0i32.to_le_bytes());
+        self.block.extend(1i32.to_le_bytes());
+        // BoxSize, Omega0, OmegaLambda, HubbleParam, flag_stellarage, flag_metals,
+        // npartTotalHighWord, flag_entropy_instead_u and the padding
+        self.block.resize(256, 0);
+        self.write_block(&mut out)?;
+
+        for b in bodies {
+            for value in [b.position[0], b.position[1], 0f64] {
+                self.block.extend((value as f32).to_le_bytes());
+            }
+        }
+        self.write_block(&mut out)?;
+
+        for b in bodies {
+            for value in [b.velocity[0], b.velocity[1], 0f64] {
+                self.block.extend((value as f32).to_le_bytes());
+            }
+        }
+        self.write_block(&mut out)?;
+
+        for b in bodies {
+            self.block.extend((b.id as u32).to_le_bytes());
+        }
+        self.write_block(&mut out)?;
+
+        for b in bodies {
+            self.block.extend((b.mass as f32).to_le_bytes());
+        }
+        self.write_block(&mut out)?;
+
+        out.flush()
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        // every frame is flushed when its file is complete
+        Ok(())
+    }
+}
+
+/// A frame held back by `TriggerBuffer`.
+pub(crate) struct BufferedFrame {
+    pub(crate) step: usize,
+    pub(crate) time: f64,
+    pub(crate) bodies: Vec<Body>,
+    /// Specific energy of every body, see `TrajectoryWriter::write_frame`
+    pub(crate) energies: Option<Vec<f64>>,
+}
+
+/// Reduces frames to the bodies whose position changed by more than a threshold since
+/// they were last written. Every body is written in the first frame it appears in.
+pub(crate) struct DeltaFilter {
+    threshold: f64,
+    /// Position of every body in the frame it was last written in
+    written: HashMap<usize, [f64; 2]>,
+}
+
+impl DeltaFilter {
+    /// * `threshold`: Distance a body has to move before it is written again.
+    pub(crate) fn new(threshold: f64) -> Self {
+        DeltaFilter {
+            threshold,
+            written: HashMap::new(),
+        }
+    }
+
+    /// Drop the bodies of the frame which did not move far enough since they were last
+    /// written and remember the positions of the remaining ones.
+    ///
+    /// * `frame`: Frame with all bodies.
+    pub(crate) fn apply(&mut self, frame: BufferedFrame) -> BufferedFrame {
+        let changed: Vec<bool> = frame
+            .bodies
+            .iter()
+            .map(|b| match self.written.get(&b.id) {
+                Some(p) => f64::hypot(b.position[0] - p[0], b.position[1] - p[1]) > self.threshold,
+                None => true,
+            })
+            .collect();
+        for (b, _) in frame.bodies.iter().zip(&changed).filter(|(_, &c)| c) {
+            self.written.insert(b.id, b.position);
+        }
+
+        BufferedFrame {
+            step: frame.step,
+            time: frame.time,
+            bodies: frame
+                .bodies
+                .into_iter()
+                .zip(&changed)
+                .filter(|(_, &c)| c)
+                .map(|(b, _)| b)
+                .collect(),
+            energies: frame.energies.map(|energies| {
+                energies
+                    .into_iter()
+                    .zip(&changed)
+                    .filter(|(_, &c)| c)
+                    .map(|(e, _)| e)
+                    .collect()
+            }),
+        }
+    }
+}
+
+/// Holds frames back until a trigger fires, then releases up to `before` frames
+/// preceding it, the triggering frame and the `after` frames following it.
+pub(crate) struct TriggerBuffer {
+    before: usize,
+    after: usize,
+    /// Frames still to be released after the last trigger
+    remaining: usize,
+    held: VecDeque<BufferedFrame>,
+}
+
+impl TriggerBuffer {
+    /// * `before`: Number of frames kept before a trigger.
+    /// * `after`: Number of frames released after a trigger.
+    pub(crate) fn new(before: usize, after: usize) -> Self {
+        TriggerBuffer {
+            before,
+            after,
+            remaining: 0,
+            held: VecDeque::with_capacity(before + 1),
+        }
+    }
+
+    /// Add a frame and return the frames to be written now, in order.
+    ///
+    /// * `frame`: Next frame.
+    /// * `triggered`: Whether the trigger condition is met in this frame.
+    pub(crate) fn push(&mut self, frame: BufferedFrame, triggered: bool) -> Vec<BufferedFrame> {
+        if triggered {
+            self.remaining = self.after;
+            let mut frames: Vec<BufferedFrame> = self.held.drain(..).collect();
+            frames.push(frame);
+            frames
+        } else if self.remaining > 0 {
+            self.remaining -= 1;
+            vec![frame]
+        } else {
+            self.held.push_back(frame);
+            if self.held.len() > self.before {
+                self.held.pop_front();
+            }
+            Vec::new()
+        }
+    }
+}
+
+/// Writes one row of energy and momentum diagnostics per step as CSV.
+pub(crate) struct DiagnosticsWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> DiagnosticsWriter<W> {
+    pub(crate) fn new(mut out: W) -> std::io::Result<Self> {
+        writeln!(
+            out,
+            "step,time,kinetic,potential,total,momentum_x,momentum_y,virial"
+        )?;
+        Ok(DiagnosticsWriter { out })
+    }
+
+    /// Write the diagnostics of one step.
+    ///
+    /// * `step`: Number of the step.
+    /// * `time`: Simulated time of the step.
+    /// * `energy`: Energy of the system.
+    /// * `momentum`: Total momentum of the system.
+    pub(crate) fn write_row(
+        &mut self,
+        step: usize,
+        time: f64,
+        energy: &Energy,
+        momentum: &[f64; 2],
+    ) -> std::io::Result<()> {
+        writeln!(
+            self.out,
+            "{},{},{},{},{},{},{},{}",
+            step,
+            time,
+            energy.kinetic,
+            energy.potential,
+            energy.total(),
+            momentum[0],
+            momentum[1],
+            energy.virial_ratio()
+        )
+    }
+}
+
+/// Writes the radial density profile of every snapshot as CSV with the header
+/// `step,r_min,r_max,density`, one row per ring.
+pub(crate) struct DensityProfileWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> DensityProfileWriter<W> {
+    pub(crate) fn new(mut out: W) -> std::io::Result<Self> {
+        writeln!(out, "step,r_min,r_max,density")?;
+        Ok(DensityProfileWriter { out })
+    }
+
+    /// Write the profile of one step.
+    ///
+    /// * `step`: Number of the step.
+    /// * `width`: Width of every ring, the first one starts at the center of mass.
+    /// * `densities`: Mass per area of every ring.
+    pub(crate) fn write_profile(
+        &mut self,
+        step: usize,
+        width: f64,
+        densities: &[f64],
+    ) -> std::io::Result<()> {
+        for (i, density) in densities.iter().enumerate() {
+            writeln!(
+                self.out,
+                "{},{},{},{}",
+                step,
+                i as f64 * width,
+                (i + 1) as f64 * width,
+                density
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the field at probe positions as CSV with the header `x,y,fx,fy`, one batch of
+/// probes at a time.
+pub(crate) struct FieldWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> FieldWriter<W> {
+    pub(crate) fn new(mut out: W) -> std::io::Result<Self> {
+        writeln!(out, "x,y,fx,fy")?;
+        Ok(FieldWriter { out })
+    }
+
+    /// Write a batch of probes.
+    ///
+    /// * `field`: Probe positions together with the force per unit mass at them.
+    pub(crate) fn write_batch(&mut self, field: &[([f64; 2], [f64; 2])]) -> std::io::Result<()> {
+        for (position, force) in field {
+            writeln!(
+                self.out,
+                "{},{},{},{}",
+                position[0], position[1], force[0], force[1]
+            )?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Write a histogram of pair distances as CSV with the header `r_min,r_max,count`.
+///
+/// * `path`: Path of the output file.
+/// * `bin_width`: Width of every bin, the first one starts at 0.
+/// * `counts`: Number of pairs in each bin.
+pub(crate) fn write_histogram(path: &str, bin_width: f64, counts: &[u64]) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "r_min,r_max,count")?;
+    for (i, count) in counts.iter().enumerate() {
+        writeln!(
+            out,
+            "{},{},{}",
+            i as f64 * bin_width,
+            (i + 1) as f64 * bin_width,
+            count
+        )?;
+    }
+    out.flush()
+}
+
+/// Write every inner node of the tree as CSV with the header
+/// `depth,center_x,center_y,size,mass,com_x,com_y`, parents before their children.
+///
+/// * `path`: Path of the output file.
+/// * `tree`: Root of the tree.
+pub(crate) fn write_tree_coms(path: &str, tree: &TreeNode) -> std::io::Result<()> {
+    fn write_node(out: &mut impl Write, node: &TreeNode, depth: usize) -> std::io::Result<()> {
+        if node.children.is_empty() {
+            return Ok(());
+        }
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{}",
+            depth,
+            node.center[0],
+            node.center[1],
+            node.size,
+            node.mass,
+            node.mass_center[0],
+            node.mass_center[1]
+        )?;
+        for child in node.children.iter() {
+            write_node(out, child, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "depth,center_x,center_y,size,mass,com_x,com_y")?;
+    write_node(&mut out, tree, 0)?;
+    out.flush()
+}
+
+/// Path of the output file of one rank, e.g. `out_rank03.csv` for `out.csv`.
+///
+/// * `path`: Path of the output file of the whole system.
+/// * `rank`: Rank writing to the file.
+pub(crate) fn per_rank_path(path: &str, rank: usize) -> String {
+    let path = std::path::Path::new(path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}_rank{:02}.{}", stem, rank, extension.to_string_lossy()),
+        None => format!("{}_rank{:02}", stem, rank),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Path of the file of one frame, e.g. `snap_000042.dat` for step 42 of `snap.dat`.
+///
+/// * `path`: Path of the output.
+/// * `step`: Step of the frame.
+pub(crate) fn frame_path(path: &str, step: usize) -> String {
+    let path = std::path::Path::new(path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}_{:06}.{}", stem, step, extension.to_string_lossy()),
+        None => format!("{}_{:06}", stem, step),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Open the output file and create a writer for the requested format.
+///
+/// `gadget-like` creates one file per frame instead, see `frame_path`.
+///
+/// * `path`: Path of the output file.
+/// * `format`: Format to write in.
+/// * `n_bodies`: Number of bodies every frame will contain.
+/// * `flush_every`: Number of frames after which the file is flushed.
+/// * `buffer_bytes`: Capacity of the write buffer, it is written to the file when full.
+/// * `with_energy`: Whether the specific energy of every body is written as well.
+pub(crate) fn create_writer(
+    path: &str,
+    format: OutputFormat,
+    n_bodies: usize,
+    flush_every: usize,
+    buffer_bytes: usize,
+    with_energy: bool,
+) -> std::io::Result<Box<dyn TrajectoryWriter>> {
+    let create = || File::create(path).map(|file| BufWriter::with_capacity(buffer_bytes, file));
+    Ok(match format {
+        OutputFormat::Csv => Box::new(FlushEvery::new(
+            CsvWriter::new(create()?, with_energy)?,
+            flush_every,
+        )),
+        OutputFormat::Chunked => Box::new(FlushEvery::new(
+            ChunkedWriter::new(create()?, n_bodies, with_energy)?,
+            flush_every,
+        )),
+        OutputFormat::GadgetLike => Box::new(GadgetWriter::new(path, buffer_bytes)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_bodies(n: usize, shift: f64) -> Vec<Body> {
+        (0..n)
+            .map(|id| Body {
+                id,
+                mass: 1f64 + id as f64,
+                position: [id as f64 + shift, -shift],
+                velocity: [0.5, -0.25 * id as f64],
+                radius: 0.1,
+                tag: id as u8,
+            })
+            .collect()
+    }
+
+    fn take<'a>(rest: &mut &'a [u8], n: usize) -> &'a [u8] {
+        let (head, tail) = rest.split_at(n);
+        *rest = tail;
+        head
+    }
+
+    fn take_u64(rest: &mut &[u8]) -> u64 {
+        u64::from_le_bytes(take(rest, 8).try_into().unwrap())
+    }
+
+    fn take_u32(rest: &mut &[u8]) -> u32 {
+        u32::from_le_bytes(take(rest, 4).try_into().unwrap())
+    }
+
+    /// Step, time and the values of every body of one frame.
+    type Frame = (u64, f64, Vec<Vec<f64>>);
+
+    /// Minimal reader of the chunked format following the layout in the module docs.
+    fn read_chunked(mut rest: &[u8]) -> (Vec<String>, Vec<Frame>) {
+        assert_eq!(take(&mut rest, 4), CHUNKED_MAGIC);
+        assert_eq!(take_u32(&mut rest), CHUNKED_VERSION);
+        let n_bodies = take_u64(&mut rest) as usize;
+        let n_fields = take_u32(&mut rest) as usize;
+        let fields: Vec<String> = (0..n_fields)
+            .map(|_| {
+                let length = take_u32(&mut rest) as usize;
+                String::from_utf8(take(&mut rest, length).to_vec()).unwrap()
+            })
+            .collect();
+
+        let mut frames = Vec::new();
+        while !rest.is_empty() {
+            assert_eq!(take_u64(&mut rest) as usize, 16 + n_bodies * n_fields * 8);
+            let step = take_u64(&mut rest);
+            let time = f64::from_bits(take_u64(&mut rest));
+            let rows = (0..n_bodies)
+                .map(|_| {
+                    let id = take_u64(&mut rest) as f64;
+                    let values: Vec<f64> = (1..n_fields)
+                        .map(|_| f64::from_bits(take_u64(&mut rest)))
+                        .collect();
+                    [vec![id], values].concat()
+                })
+                .collect();
+            frames.push((step, time, rows));
+        }
+        (fields, frames)
+    }
+
+    #[test]
+    fn chunked_output_round_trips() {
+        let mut bytes = Vec::new();
+        let mut writer = ChunkedWriter::new(&mut bytes, 3, false).unwrap();
+        for step in 0..4 {
+            let bodies = test_bodies(3, step as f64);
+            writer
+                .write_frame(step, step as f64 * 0.5, &bodies, None)
+                .unwrap();
+        }
+        drop(writer);
+
+        let (fields, frames) = read_chunked(&bytes);
+        assert_eq!(fields, FIELDS);
+        assert_eq!(frames.len(), 4);
+        for (step, (read_step, time, rows)) in frames.into_iter().enumerate() {
+            assert_eq!((read_step, time), (step as u64, step as f64 * 0.5));
+            for (row, b) in rows.iter().zip(test_bodies(3, step as f64)) {
+                let expected = [
+                    b.id as f64,
+                    b.mass,
+                    b.position[0],
+                    b.position[1],
+                    b.velocity[0],
+                    b.velocity[1],
+                    b.radius,
+                    b.tag as f64,
+                ];
+                assert_eq!(row[..], expected);
+            }
+        }
+    }
+
+    #[test]
+    fn csv_output_has_step_and_time_columns() {
+        let mut bytes = Vec::new();
+        let mut writer = CsvWriter::new(&mut bytes, false).unwrap();
+        writer
+            .write_frame(2, 0.5, &test_bodies(2, 0f64), None)
+            .unwrap();
+
+        let text = String::from_utf8(bytes).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "step,time,id,mass,x,y,vx,vy,radius,tag");
+        assert_eq!(
+            lines[1..],
+            [
+                "2,0.5,0,1,0,-0,0.5,-0,0.1,0",
+                "2,0.5,1,2,1,-0,0.5,-0.25,0.1,1"
+            ]
+        );
+    }
+
+    #[test]
+    fn body_frame_puts_reference_body_at_origin() {
+        let frame: ReferenceFrame = "body:0".parse().unwrap();
+        for step in 0..3 {
+            let bodies = to_frame(&test_bodies(3, step as f64), frame).unwrap();
+            assert_eq!(bodies[0].position, [0f64, 0f64]);
+            assert_eq!(bodies[0].velocity, [0f64, 0f64]);
+            assert_eq!(bodies[2].position, [2f64, 0f64]);
+        }
+        assert!(to_frame(&test_bodies(3, 0f64), ReferenceFrame::Body(3)).is_err());
+    }
+
+    #[test]
+    fn com_frame_centers_on_center_of_mass() {
+        let bodies = to_frame(&test_bodies(3, 1f64), ReferenceFrame::Com).unwrap();
+        let moment = bodies.iter().fold([0f64; 2], |m, b| {
+            [m[0] + b.mass * b.position[0], m[1] + b.mass * b.position[1]]
+        });
+        assert!(moment.iter().all(|m| m.abs() < 1e-12), "{:?}", moment);
+    }
+
+    #[test]
+    fn diagnostics_have_one_row_per_step_and_consistent_total() {
+        let mut bytes = Vec::new();
+        let mut writer = DiagnosticsWriter::new(&mut bytes).unwrap();
+        for step in 0..5 {
+            let energy = Energy {
+                kinetic: 1.5 + step as f64,
+                potential: -3.25 * step as f64,
+            };
+            writer
+                .write_row(step, step as f64 * 0.1, &energy, &[0.5, -0.5])
+                .unwrap();
+        }
+
+        let text = String::from_utf8(bytes).unwrap();
+        let rows: Vec<Vec<f64>> = text
+            .lines()
+            .skip(1)
+            .map(|l| l.split(',').map(|v| v.parse().unwrap()).collect())
+            .collect();
+        assert_eq!(rows.len(), 5);
+        for (step, row) in rows.iter().enumerate() {
+            assert_eq!(row.len(), 8);
+            assert_eq!(row[0], step as f64);
+            assert_eq!(row[4], row[2] + row[3]);
+        }
+    }
+
+    /// Records after how many frames every flush happened.
+    #[derive(Default)]
+    struct CountingWriter {
+        frames: usize,
+        flushed_at: Vec<usize>,
+    }
+
+    impl TrajectoryWriter for CountingWriter {
+        fn write_frame(
+            &mut self,
+            _step: usize,
+            _time: f64,
+            _bodies: &[Body],
+            _energies: Option<&[f64]>,
+        ) -> std::io::Result<()> {
+            self.frames += 1;
+            Ok(())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.flushed_at.push(self.frames);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flushes_every_n_frames() {
+        let bodies = test_bodies(2, 0f64);
+        let mut writer = FlushEvery::new(CountingWriter::default(), 3);
+        for step in 0..10 {
+            writer
+                .write_frame(step, step as f64, &bodies, None)
+                .unwrap();
+        }
+        assert_eq!(writer.inner.flushed_at, vec![3, 6, 9]);
+    }
+
+    #[test]
+    fn root_row_of_tree_dump_holds_total_mass() {
+        let bodies = test_bodies(5, 0.25);
+        let mut tree = TreeNode {
+            size: 16f64,
+            ..TreeNode::default()
+        };
+        for b in &bodies {
+            tree.insert(b);
+        }
+        let path = std::env::temp_dir().join(format!("tree_coms_{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_tree_coms(path, &tree).unwrap();
+        let text = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let mut lines = text.lines();
+        assert_eq!(
+            lines.next(),
+            Some("depth,center_x,center_y,size,mass,com_x,com_y")
+        );
+        let root: Vec<f64> = lines
+            .next()
+            .unwrap()
+            .split(',')
+            .map(|v| v.parse().unwrap())
+            .collect();
+        let total_mass: f64 = bodies.iter().map(|b| b.mass).sum();
+        assert_eq!(root[0], 0f64);
+        assert!((root[4] - total_mass).abs() < 1e-12, "{:?}", root);
+        assert!(lines.all(|l| !l.starts_with("0,")));
+    }
+
+    #[test]
+    fn region_keeps_only_the_bodies_inside() {
+        let region: Region = "0.5,2.5,-1,0".parse().unwrap();
+        let frame = BufferedFrame {
+            step: 3,
+            time: 1.5,
+            bodies: test_bodies(4, 0f64),
+            energies: Some(vec![-1f64, -2f64, -3f64, -4f64]),
+        };
+        let frame = region.filter(frame);
+        assert_eq!((frame.step, frame.time), (3, 1.5));
+        assert_eq!(
+            frame.bodies.iter().map(|b| b.id).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        assert_eq!(frame.energies, Some(vec![-2f64, -3f64]));
+    }
+
+    #[test]
+    fn trigger_writes_frames_around_a_close_approach() {
+        // two bodies passing each other at a vertical offset of 1, closest at step 10
+        let mut buffer = TriggerBuffer::new(2, 2);
+        let mut written = Vec::new();
+        for step in 0..30 {
+            let x = step as f64 - 10f64;
+            let mut bodies = test_bodies(2, 0f64);
+            bodies[0].position = [x, 0.5];
+            bodies[1].position = [-x, -0.5];
+            let triggered = crate::diagnostics::any_pair_within(&bodies, 3f64);
+            let frame = BufferedFrame {
+                step,
+                time: step as f64,
+                bodies,
+                energies: None,
+            };
+            written.extend(buffer.push(frame, triggered).into_iter().map(|f| f.step));
+        }
+        // steps 9 to 11 are within the distance, plus two frames on either side
+        assert_eq!(written, (7..=13).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn per_rank_outputs_concatenate_to_the_full_state() {
+        let (n_bodies, n_proc) = (10, 4);
+        let removed = std::collections::HashSet::new();
+        let padded = |shift: f64| {
+            let mut bodies = test_bodies(n_bodies, shift);
+            bodies.extend((n_bodies..12).map(|id| Body {
+                id,
+                ..Body::default()
+            }));
+            bodies
+        };
+        let frames = [padded(0f64), padded(1f64)];
+
+        let directory = std::env::temp_dir();
+        let path = directory.join(format!("per_rank_{}.csv", std::process::id()));
+        let mut rank_rows = Vec::new();
+        for rank in 0..n_proc {
+            let rank_path = per_rank_path(path.to_str().unwrap(), rank);
+            let mut writer =
+                create_writer(&rank_path, OutputFormat::Csv, 3, 1, 1024, false).unwrap();
+            for (step, all_bodies) in frames.iter().enumerate() {
+                let local = &all_bodies[rank * 3..(rank + 1) * 3];
+                let bodies = crate::partition::real_bodies(local, n_bodies, &removed);
+                writer
+                    .write_frame(step, step as f64, &bodies, None)
+                    .unwrap();
+            }
+            writer.flush().unwrap();
+            drop(writer);
+            let text = std::fs::read_to_string(&rank_path).unwrap();
+            std::fs::remove_file(&rank_path).unwrap();
+            rank_rows.extend(text.lines().skip(1).map(str::to_string));
+        }
+
+        let mut bytes = Vec::new();
+        let mut writer = CsvWriter::new(&mut bytes, false).unwrap();
+        for (step, all_bodies) in frames.iter().enumerate() {
+            let bodies = crate::partition::real_bodies(all_bodies, n_bodies, &removed);
+            writer
+                .write_frame(step, step as f64, &bodies, None)
+                .unwrap();
+        }
+        let text = String::from_utf8(bytes).unwrap();
+        let full_rows: Vec<String> = text.lines().skip(1).map(str::to_string).collect();
+
+        // the rows of one step are split between the files, sorting by step and id joins them
+        let key = |row: &String| {
+            let columns: Vec<&str> = row.split(',').collect();
+            let [step, id] = [columns[0], columns[2]].map(|v| v.parse::<usize>().unwrap());
+            (step, id)
+        };
+        rank_rows.sort_by_key(key);
+        assert_eq!(rank_rows, full_rows);
+    }
+
+    #[test]
+    fn gadget_snapshot_round_trips() {
+        let bodies = test_bodies(3, 0.5);
+        let path = std::env::temp_dir().join(format!("snap_{}.dat", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut writer = create_writer(path, OutputFormat::GadgetLike, 3, 1, 1024, false).unwrap();
+        writer.write_frame(42, 1.5, &bodies, None).unwrap();
+        let frame = frame_path(path, 42);
+        let bytes = std::fs::read(&frame).unwrap();
+        std::fs::remove_file(&frame).unwrap();
+
+        // every block is framed by its length before and after it
+        let mut rest = bytes.as_slice();
+        let mut blocks = Vec::new();
+        while !rest.is_empty() {
+            let length = take_u32(&mut rest) as usize;
+            blocks.push(take(&mut rest, length));
+            assert_eq!(take_u32(&mut rest) as usize, length);
+        }
+        let [head, positions, velocities, ids, masses] = blocks[..] else {
+            panic!("expected 5 blocks, got {}", blocks.len());
+        };
+        let f32s = |block: &[u8]| -> Vec<f64> {
+            block
+                .chunks(4)
+                .map(|c| f32::from_le_bytes(c.try_into().unwrap()) as f64)
+                .collect()
+        };
+
+        assert_eq!(head.len(), 256);
+        assert_eq!(u32::from_le_bytes(head[4..8].try_into().unwrap()), 3);
+        assert_eq!(f64::from_le_bytes(head[72..80].try_into().unwrap()), 1.5);
+        let ids: Vec<usize> = ids
+            .chunks(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()) as usize)
+            .collect();
+        assert_eq!(ids, [0, 1, 2]);
+        let [positions, velocities] = [positions, velocities].map(f32s);
+        for (i, b) in bodies.iter().enumerate() {
+            assert_eq!(
+                positions[3 * i..3 * i + 3],
+                [b.position[0], b.position[1], 0f64]
+            );
+            assert_eq!(
+                velocities[3 * i..3 * i + 3],
+                [b.velocity[0], b.velocity[1], 0f64]
+            );
+        }
+        assert_eq!(
+            f32s(masses),
+            bodies.iter().map(|b| b.mass).collect::<Vec<f64>>()
+        );
+    }
+
+    #[test]
+    fn stride_ramp_writes_early_frames_densely() {
+        let ramp: StrideRamp = "1,100".parse().unwrap();
+        let n_steps = 10000;
+        let mut frames = vec![0];
+        while let Some(&step) = frames.last().filter(|&&s| s < n_steps) {
+            frames.push(step + ramp.stride(step, n_steps));
+        }
+
+        assert_eq!(&frames[..3], &[0, 1, 2]);
+        assert_eq!(ramp.stride(n_steps, n_steps), 100);
+        // the stride grows geometrically, so the first tenth holds most of the frames
+        let in_range =
+            |from: usize, to: usize| frames.iter().filter(|&&s| (from..to).contains(&s)).count();
+        let (early, late) = (in_range(0, 1000), in_range(9000, 10000));
+        assert!(early > 10 * late, "{} {}", early, late);
+        assert!(frames.windows(2).all(|w| w[1] - w[0] <= 100));
+        assert!("0,10".parse::<StrideRamp>().is_err());
+    }
+
+    #[test]
+    fn delta_frames_reconstruct_the_trajectory() {
+        let threshold = 0.3;
+        // body 0 stays put, body 1 moves steadily and body 2 jumps now and then
+        let frames: Vec<Vec<Body>> = (0..20)
+            .map(|step| {
+                let mut bodies = test_bodies(3, 0f64);
+                bodies[1].position[1] = 0.1 * step as f64;
+                bodies[2].position[0] += (step / 7) as f64;
+                bodies
+            })
+            .collect();
+
+        let mut filter = DeltaFilter::new(threshold);
+        let mut bytes = Vec::new();
+        let mut writer = CsvWriter::new(&mut bytes, false).unwrap();
+        for (step, bodies) in frames.iter().enumerate() {
+            let frame = filter.apply(BufferedFrame {
+                step,
+                time: step as f64,
+                bodies: bodies.clone(),
+                energies: None,
+            });
+            writer
+                .write_frame(step, frame.time, &frame.bodies, None)
+                .unwrap();
+        }
+
+        // the full state of a step is the last row of every id up to it
+        let text = String::from_utf8(bytes).unwrap();
+        let rows: Vec<Vec<f64>> = text
+            .lines()
+            .skip(1)
+            .map(|l| l.split(',').map(|v| v.parse().unwrap()).collect())
+            .collect();
+        assert!(rows.len() < 3 * frames.len() / 2, "{} rows", rows.len());
+        let mut state = HashMap::new();
+        let mut next_row = 0;
+        for (step, bodies) in frames.iter().enumerate() {
+            while next_row < rows.len() && rows[next_row][0] as usize == step {
+                let row = &rows[next_row];
+                state.insert(row[2] as usize, [row[4], row[5]]);
+                next_row += 1;
+            }
+            assert_eq!(state.len(), bodies.len());
+            for b in bodies {
+                let p = state[&b.id];
+                let error = f64::hypot(b.position[0] - p[0], b.position[1] - p[1]);
+                assert!(error <= threshold, "step {} body {}: {}", step, b.id, error);
+            }
+        }
+    }
+
+    #[test]
+    fn output_memory_stays_bounded_over_many_frames() {
+        use std::cell::Cell;
+        use std::io::BufWriter;
+        use std::rc::Rc;
+
+        /// Counts the bytes that reached the destination, like a file on disk.
+        struct Sink(Rc<Cell<usize>>);
+
+        impl Write for Sink {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.set(self.0.get() + buf.len());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buffer_bytes = 512;
+        let delivered = Rc::new(Cell::new(0));
+        let sink = BufWriter::with_capacity(buffer_bytes, Sink(delivered.clone()));
+        let mut writer = CsvWriter::new(sink, false).unwrap();
+        // the same frames written on their own give the number of bytes produced
+        let mut produced = CsvWriter::new(Vec::new(), false).unwrap().out.len();
+        let mut trigger = TriggerBuffer::new(3, 1);
+        for step in 0..20_000 {
+            let bodies = test_bodies(4, step as f64);
+            let mut frame_bytes = CsvWriter {
+                out: Vec::new(),
+                with_energy: false,
+            };
+            frame_bytes.write_frame(step, 0.5, &bodies, None).unwrap();
+            produced += frame_bytes.out.len();
+            writer.write_frame(step, 0.5, &bodies, None).unwrap();
+            // only the buffer's content has not reached the destination yet
+            assert!(produced - delivered.get() <= buffer_bytes, "step {}", step);
+
+            let frame = BufferedFrame {
+                step,
+                time: 0.5,
+                bodies,
+                energies: None,
+            };
+            trigger.push(frame, step % 1000 == 999);
+            assert!(trigger.held.len() <= 3);
+        }
+        writer.flush().unwrap();
+        assert_eq!(delivered.get(), produced);
+    }
+}
@@ -0,0 +1,434 @@
+//! Distribution of the bodies among the processes.
+//!
+//! Every process owns the slice `all_bodies[rank * bodies_per_proc..]`. Padding bodies
+//! (zero mass, `id >= n_bodies`) fill up the slices so that all have the same length.
+//! Bodies removed from the simulation, e.g. absorbed in a collision, keep their id and
+//! are treated like padding, see `is_real`. They keep their slot as well, until `remove`
+//! drops them from all bodies.
+
+use super::Body;
+
+use clap::ValueEnum;
+use std::collections::HashSet;
+
+/// How the bodies are initially distributed among the processes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum PartitionBy {
+    /// Consecutive ids, which is spatially arbitrary
+    Id,
+    /// Consecutive Morton keys, so that every process owns a spatially compact set
+    Morton,
+}
+
+/// Interleave the lower 32 bits of `x` with zeros, e.g. `0b1011 -> 0b1000101`.
+fn spread_bits(x: u64) -> u64 {
+    let mut x = x & 0xffff_ffff;
+    x = (x | (x << 16)) & 0x0000_ffff_0000_ffff;
+    x = (x | (x << 8)) & 0x00ff_00ff_00ff_00ff;
+    x = (x | (x << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+    x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+    x = (x | (x << 1)) & 0x5555_5555_5555_5555;
+    x
+}
+
+/// Morton (Z-order) key of a position within the given bounds.
+///
+/// Positions close to each other on the Z-curve are spatially close, so sorting by
+/// this key groups bodies into compact regions.
+///
+/// * `position`: Position of the body.
+/// * `bounds`: Bounds of all bodies as returned by `get_bounds`.
+pub(crate) fn morton_key(position: &[f64; 2], bounds: &[[f64; 2]; 2]) -> u64 {
+    let quantize = |value: f64, [min, max]: [f64; 2]| {
+        let extent = max - min;
+        if extent <= 0f64 {
+            return 0u64;
+        }
+        let normalized = ((value - min) / extent).clamp(0f64, 1f64);
+        (normalized * u32::MAX as f64) as u64
+    };
+
+    let x = quantize(position[0], bounds[0]);
+    let y = quantize(position[1], bounds[1]);
+    spread_bits(x) | (spread_bits(y) << 1)
+}
+
+/// Whether a body takes part in the simulation, i.e. is neither padding nor removed.
+///
+/// * `b`: Body to check.
+/// * `n_bodies`: Number of real bodies, bodies with higher ids are padding.
+/// * `removed`: Ids of the bodies removed from the simulation.
+pub(crate) fn is_real(b: &Body, n_bodies: usize, removed: &HashSet<usize>) -> bool {
+    b.id < n_bodies && !removed.contains(&b.id)
+}
+
+/// Reorder all bodies so that each process owns a spatially contiguous set of bodies and
+/// the numbers of real bodies of the processes differ by at most one.
+///
+/// Real bodies are sorted by their Morton key and cut into `n_proc` consecutive pieces,
+/// each slice is then filled up with padding bodies.
+///
+/// * `all_bodies`: Bodies of all processes, including the padding.
+/// * `n_bodies`: Number of real bodies.
+/// * `removed`: Ids of the bodies removed from the simulation.
+/// * `n_proc`: Number of processes.
+/// * `bounds`: Bounds of all bodies.
+pub(crate) fn balance_by_morton(
+    all_bodies: &mut Vec<Body>,
+    n_bodies: usize,
+    removed: &HashSet<usize>,
+    n_proc: usize,
+    bounds: &[[f64; 2]; 2],
+) {
+    let bodies_per_proc = all_bodies.len() / n_proc;
+    let (mut real, mut padding): (Vec<Body>, Vec<Body>) = all_bodies
+        .drain(..)
+        .partition(|b| is_real(b, n_bodies, removed));
+    let n_real = real.len();
+
+    // sort by key and id, so that every process computes the very same order
+    real.sort_by_cached_key(|b| (morton_key(&b.position, bounds), b.id));
+    padding.sort_by_key(|b| b.id);
+
+    let mut real = real.into_iter();
+    let mut padding = padding.into_iter();
+    for rank in 0..n_proc {
+        let n_local = n_real / n_proc + usize::from(rank < n_real % n_proc);
+        all_bodies.extend(real.by_ref().take(n_local));
+        all_bodies.extend(padding.by_ref().take(bodies_per_proc - n_local));
+    }
+
+    assert_eq!(all_bodies.len(), bodies_per_proc * n_proc);
+}
+
+/// Append new bodies to the real bodies and pad all bodies again, so that every process
+/// gets the same number of them.
+///
+/// The new bodies get the ids from `n_bodies` on, in their order. The real bodies keep
+/// their order, followed by the new bodies and the padding. Returns the new number of
+/// real bodies.
+///
+/// * `all_bodies`: Bodies of all processes, including the padding.
+/// * `n_bodies`: Number of real bodies.
+/// * `new_bodies`: Bodies to add.
+/// * `n_proc`: Number of processes.
+pub(crate) fn inject(
+    all_bodies: &mut Vec<Body>,
+    n_bodies: usize,
+    new_bodies: Vec<Body>,
+    n_proc: usize,
+) -> usize {
+    all_bodies.retain(|b| b.id < n_bodies);
+    let n_new = new_bodies.len();
+    all_bodies.extend(new_bodies.into_iter().enumerate().map(|(i, b)| Body {
+        id: n_bodies + i,
+        ..b
+    }));
+
+    pad(all_bodies, n_bodies + n_new, n_proc);
+    n_bodies + n_new
+}
+
+/// Remove the real bodies matching `escaped` together with the already removed ones and
+/// pad all bodies again, so that every process gets the same number of them.
+///
+/// The remaining bodies keep their order and ids. The ids of the removed bodies are added
+/// to `removed`, so they are neither reused nor mistaken for real bodies. Returns the
+/// number of newly removed bodies.
+///
+/// * `all_bodies`: Bodies of all processes, including the padding.
+/// * `n_bodies`: Number of real bodies.
+/// * `removed`: Ids of the bodies removed from the simulation.
+/// * `n_proc`: Number of processes.
+/// * `escaped`: Whether a real body is removed.
+pub(crate) fn remove(
+    all_bodies: &mut Vec<Body>,
+    n_bodies: usize,
+    removed: &mut HashSet<usize>,
+    n_proc: usize,
+    escaped: impl Fn(&Body) -> bool,
+) -> usize {
+    let n_removed = removed.len();
+    all_bodies.retain(|b| {
+        if !is_real(b, n_bodies, removed) {
+            return false;
+        }
+        if escaped(b) {
+            removed.insert(b.id);
+            return false;
+        }
+        true
+    });
+
+    pad(all_bodies, n_bodies, n_proc);
+    removed.len() - n_removed
+}
+
+/// Append padding bodies, so that the number of all bodies is divisible by the number of
+/// processes.
+///
+/// * `all_bodies`: Bodies without padding, all with ids below `n_bodies`.
+/// * `n_bodies`: Number of real bodies, the first id of the padding.
+/// * `n_proc`: Number of processes.
+fn pad(all_bodies: &mut Vec<Body>, n_bodies: usize, n_proc: usize) {
+    let n_padding = all_bodies.len().div_ceil(n_proc) * n_proc - all_bodies.len();
+    all_bodies.extend((n_bodies..n_bodies + n_padding).map(|id| Body {
+        id,
+        ..Body::default()
+    }));
+}
+
+/// Sort bodies by their Morton key, so that consecutive bodies are spatially close.
+///
+/// Inserting bodies in this order into the tree touches neighbouring nodes one after
+/// another. Only the order within the slice changes, not which process owns a body.
+///
+/// * `bodies`: Bodies to sort.
+/// * `bounds`: Bounds of all bodies.
+pub(crate) fn sort_by_morton(bodies: &mut [Body], bounds: &[[f64; 2]; 2]) {
+    bodies.sort_by_cached_key(|b| (morton_key(&b.position, bounds), b.id));
+}
+
+/// Check the order of the gathered bodies. The processes own consecutive ranges of the
+/// bodies and the gather concatenates them in rank order, so unless the bodies were
+/// reordered (e.g. by `balance_by_morton` or `sort_by_morton`), their ids increase.
+/// Injecting, removing and merging bodies keep this order, removed bodies only leave gaps
+/// in the ids. Reordered bodies still have to hold every id at most once and every real
+/// body.
+///
+/// * `all_bodies`: Bodies of all processes, including the padding.
+/// * `n_bodies`: Number of real bodies.
+/// * `removed`: Ids of the bodies removed from the simulation.
+/// * `ordered`: Whether the bodies have to be in id order.
+pub(crate) fn check_order(
+    all_bodies: &[Body],
+    n_bodies: usize,
+    removed: &HashSet<usize>,
+    ordered: bool,
+) -> Result<(), String> {
+    if ordered {
+        if let Some(pair) = all_bodies.windows(2).find(|w| w[0].id >= w[1].id) {
+            return Err(format!("body {} follows body {}", pair[1].id, pair[0].id));
+        }
+    }
+
+    // the padding has the ids from n_bodies on, so all ids are below this
+    let mut seen = vec![false; n_bodies + all_bodies.len()];
+    for (i, b) in all_bodies.iter().enumerate() {
+        match seen.get(b.id).copied() {
+            Some(false) => seen[b.id] = true,
+            Some(true) => return Err(format!("body {} occurs twice, again at index {}", b.id, i)),
+            None => return Err(format!("body {} at index {} has no valid id", b.id, i)),
+        }
+    }
+    match (0..n_bodies).find(|id| !seen[*id] && !removed.contains(id)) {
+        Some(id) => Err(format!("body {} is missing", id)),
+        None => Ok(()),
+    }
+}
+
+/// Real bodies sorted by id, independent of how they are distributed among processes.
+///
+/// * `all_bodies`: Bodies of all processes, including the padding.
+/// * `n_bodies`: Number of real bodies.
+/// * `removed`: Ids of the bodies removed from the simulation.
+pub(crate) fn real_bodies(
+    all_bodies: &[Body],
+    n_bodies: usize,
+    removed: &HashSet<usize>,
+) -> Vec<Body> {
+    let mut bodies: Vec<Body> = all_bodies
+        .iter()
+        .filter(|b| is_real(b, n_bodies, removed))
+        .cloned()
+        .collect();
+    bodies.sort_by_key(|b| b.id);
+    bodies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `n_bodies` real bodies on a spiral, padded for `n_proc` processes.
+    fn padded_bodies(n_bodies: usize, n_proc: usize) -> Vec<Body> {
+        let mut bodies: Vec<Body> = (0..n_bodies)
+            .map(|id| Body {
+                id,
+                mass: 1f64,
+                position: [id as f64 * (id as f64).cos(), id as f64 * (id as f64).sin()],
+                ..Body::default()
+            })
+            .collect();
+        pad(&mut bodies, n_bodies, n_proc);
+        bodies
+    }
+
+    #[test]
+    fn balancing_equalizes_populations_and_keeps_bodies() {
+        let (n_bodies, n_proc) = (10, 4);
+        let mut bodies = padded_bodies(n_bodies, n_proc);
+        let bounds = [[-10f64, 10f64], [-10f64, 10f64]];
+        balance_by_morton(&mut bodies, n_bodies, &HashSet::new(), n_proc, &bounds);
+
+        assert_eq!(bodies.len(), 12);
+        assert_eq!(
+            check_order(&bodies, n_bodies, &HashSet::new(), false),
+            Ok(())
+        );
+        let populations: Vec<usize> = bodies
+            .chunks(bodies.len() / n_proc)
+            .map(|c| c.iter().filter(|b| b.id < n_bodies).count())
+            .collect();
+        assert_eq!(populations.iter().sum::<usize>(), n_bodies);
+        assert!(populations.iter().max().unwrap() - populations.iter().min().unwrap() <= 1);
+
+        let keys: Vec<u64> = bodies
+            .iter()
+            .filter(|b| b.id < n_bodies)
+            .map(|b| morton_key(&b.position, &bounds))
+            .collect();
+        assert!(keys.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn removed_bodies_are_not_real() {
+        let mut bodies = padded_bodies(5, 4);
+        bodies[2].mass = 0f64;
+        let removed = HashSet::from([2]);
+        let ids: Vec<usize> = real_bodies(&bodies, 5, &removed)
+            .iter()
+            .map(|b| b.id)
+            .collect();
+        assert_eq!(ids, [0, 1, 3, 4]);
+
+        let bounds = [[-10f64, 10f64], [-10f64, 10f64]];
+        balance_by_morton(&mut bodies, 5, &removed, 4, &bounds);
+        assert_eq!(check_order(&bodies, 5, &removed, false), Ok(()));
+        let populations: Vec<usize> = bodies
+            .chunks(2)
+            .map(|c| c.iter().filter(|b| is_real(b, 5, &removed)).count())
+            .collect();
+        assert_eq!(populations, [1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn frames_are_in_id_order_after_rebalancing() {
+        let (n_bodies, n_proc) = (10, 4);
+        let mut bodies = padded_bodies(n_bodies, n_proc);
+        let removed = HashSet::from([3]);
+        let bounds = [[-20f64, 20f64], [-20f64, 20f64]];
+        let ids: Vec<usize> = (0..n_bodies).filter(|id| *id != 3).collect();
+
+        for step in 0..4 {
+            // move the bodies around, so that every step reorders them differently
+            for b in bodies.iter_mut().filter(|b| b.id < n_bodies) {
+                let angle = (b.id * (step + 1)) as f64;
+                b.position = [10f64 * angle.cos(), 10f64 * angle.sin()];
+            }
+            balance_by_morton(&mut bodies, n_bodies, &removed, n_proc, &bounds);
+            let chunk = bodies.len() / n_proc;
+            for local in bodies.chunks_mut(chunk) {
+                sort_by_morton(local, &bounds);
+            }
+
+            let frame: Vec<usize> = real_bodies(&bodies, n_bodies, &removed)
+                .iter()
+                .map(|b| b.id)
+                .collect();
+            assert_eq!(frame, ids, "step {}", step);
+        }
+    }
+
+    #[test]
+    fn escaped_bodies_are_removed_and_the_rest_keep_their_ids() {
+        let (n_bodies, n_proc) = (10, 4);
+        let mut bodies = padded_bodies(n_bodies, n_proc);
+        // body 2 was absorbed in a collision before
+        let mut removed = HashSet::from([2]);
+        let n_removed = remove(&mut bodies, n_bodies, &mut removed, n_proc, |b| {
+            b.position[0].hypot(b.position[1]) > 6.5
+        });
+
+        // bodies 7, 8 and 9 are farther than 6.5 from the origin
+        assert_eq!(n_removed, 3);
+        assert_eq!(removed, HashSet::from([2, 7, 8, 9]));
+        let ids: Vec<usize> = bodies.iter().map(|b| b.id).collect();
+        assert_eq!(ids, [0, 1, 3, 4, 5, 6, 10, 11]);
+        assert_eq!(bodies.len() % n_proc, 0);
+        let real = real_bodies(&bodies, n_bodies, &removed);
+        assert_eq!(real.len(), n_bodies - removed.len());
+        assert!(bodies[6..].iter().all(|b| b.mass == 0f64));
+
+        // new bodies get fresh ids, the removed ones are not reused
+        let n_bodies = inject(&mut bodies, n_bodies, vec![Body::default(); 3], n_proc);
+        assert_eq!(n_bodies, 13);
+        let ids: Vec<usize> = bodies.iter().map(|b| b.id).collect();
+        assert_eq!(ids, [0, 1, 3, 4, 5, 6, 10, 11, 12, 13, 14, 15]);
+    }
+
+    #[test]
+    fn morton_partitioning_gives_every_rank_one_quadrant() {
+        // an 8 by 8 grid, whose Z-curve passes through the quadrants one after another
+        let (n_bodies, n_proc) = (64, 4);
+        let mut bodies: Vec<Body> = (0..n_bodies)
+            .map(|id| Body {
+                id,
+                mass: 1f64,
+                position: [(id % 8) as f64, (id / 8) as f64],
+                ..Body::default()
+            })
+            .collect();
+        let bounds = [[0f64, 7f64], [0f64, 7f64]];
+        balance_by_morton(&mut bodies, n_bodies, &HashSet::new(), n_proc, &bounds);
+
+        let mut quadrants: Vec<[usize; 2]> = bodies
+            .chunks(n_bodies / n_proc)
+            .map(|local| {
+                let quadrant = |b: &Body| [0, 1].map(|dim| (b.position[dim] >= 4f64) as usize);
+                assert!(local.iter().all(|b| quadrant(b) == quadrant(&local[0])));
+                quadrant(&local[0])
+            })
+            .collect();
+        quadrants.sort();
+        assert_eq!(quadrants, [[0, 0], [0, 1], [1, 0], [1, 1]]);
+    }
+
+    #[test]
+    fn gathered_bodies_stay_in_id_order_unless_reordered() {
+        let (mut n_bodies, n_proc) = (10, 4);
+        let mut removed = HashSet::new();
+        let mut bodies = padded_bodies(n_bodies, n_proc);
+        // the gather concatenates the processes' slices in rank order, so all bodies keep
+        // the order they were split in
+        assert_eq!(check_order(&bodies, n_bodies, &removed, true), Ok(()));
+
+        n_bodies = inject(&mut bodies, n_bodies, padded_bodies(3, 1), n_proc);
+        assert_eq!(check_order(&bodies, n_bodies, &removed, true), Ok(()));
+        remove(&mut bodies, n_bodies, &mut removed, n_proc, |b| {
+            b.id % 4 == 1
+        });
+        // a body absorbed by a merge stays in place with its id removed
+        bodies[4].mass = 0f64;
+        removed.insert(bodies[4].id);
+        assert_eq!(check_order(&bodies, n_bodies, &removed, true), Ok(()));
+
+        let bounds = [[-20f64, 20f64], [-20f64, 20f64]];
+        balance_by_morton(&mut bodies, n_bodies, &removed, n_proc, &bounds);
+        assert!(check_order(&bodies, n_bodies, &removed, true).is_err());
+        assert_eq!(check_order(&bodies, n_bodies, &removed, false), Ok(()));
+
+        // a real body replaced by a copy of another one is reported either way
+        let mut broken = bodies.clone();
+        let real = |b: &Body| is_real(b, n_bodies, &removed);
+        let first_real = broken.iter().position(real).unwrap();
+        let last_real = broken.iter().rposition(real).unwrap();
+        let lost = broken[last_real].id;
+        broken[last_real] = broken[first_real].clone();
+        let error = check_order(&broken, n_bodies, &removed, false).unwrap_err();
+        assert!(error.contains("twice"), "{}", error);
+        broken.remove(last_real);
+        let error = check_order(&broken, n_bodies, &removed, false).unwrap_err();
+        assert_eq!(error, format!("body {} is missing", lost));
+    }
+}
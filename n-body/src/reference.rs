@@ -0,0 +1,168 @@
+//! Comparison of the simulation with a stored reference trajectory.
+//!
+//! The reference is a trajectory written with `--output-format csv`, e.g. by an earlier
+//! run with the same initial conditions. Its frames are matched by step and its rows by
+//! body id, only the positions are compared. A header line and lines starting with `#`
+//! are skipped.
+
+use super::input::parse_rows;
+use super::Body;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+/// Deviation of the positions of one frame from the reference.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct PositionError {
+    /// Largest distance of a body to its reference position
+    pub(crate) max: f64,
+    /// Root mean square of the distances of all bodies to their reference positions
+    pub(crate) rms: f64,
+}
+
+impl PositionError {
+    /// Fail with a message if the max or RMS error exceeds the tolerance or is NaN.
+    ///
+    /// * `tolerance`: Largest accepted error in meters.
+    pub(crate) fn check(&self, tolerance: f64) -> Result<(), String> {
+        if self.max <= tolerance && self.rms <= tolerance {
+            Ok(())
+        } else {
+            Err(format!(
+                "position error {} m exceeds the reference tolerance {} m",
+                self.max, tolerance
+            ))
+        }
+    }
+}
+
+/// Positions of the reference trajectory by step and body id.
+#[derive(Debug, Default)]
+pub(crate) struct Reference {
+    frames: HashMap<usize, HashMap<usize, [f64; 2]>>,
+}
+
+impl Reference {
+    /// Parse a reference from the lines of a CSV trajectory.
+    ///
+    /// * `lines`: Lines of the trajectory.
+    pub(crate) fn parse<I>(lines: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = std::io::Result<String>>,
+    {
+        let mut reference = Reference::default();
+
+        for row in parse_rows(lines, |_| Ok(())) {
+            let (line_number, values) = row?;
+            if values.len() < 6 {
+                return Err(format!(
+                    "line {}: expected at least 6 values (step,time,id,mass,x,y,...), got {}",
+                    line_number,
+                    values.len()
+                ));
+            }
+
+            let (step, id) = (values[0] as usize, values[2] as usize);
+            reference
+                .frames
+                .entry(step)
+                .or_default()
+                .insert(id, [values[4], values[5]]);
+        }
+
+        Ok(reference)
+    }
+
+    /// Load a reference from a CSV trajectory file, see `parse`.
+    ///
+    /// * `path`: Path of the trajectory.
+    pub(crate) fn load(path: &str) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("could not open {}: {}", path, e))?;
+        Self::parse(BufReader::new(file).lines())
+    }
+
+    /// Deviation of the bodies' positions from the reference frame of the given step.
+    ///
+    /// Returns `None` if the reference has no frame of this step and an error if a body
+    /// is missing in the reference frame.
+    ///
+    /// * `step`: Step of the bodies.
+    /// * `bodies`: Bodies to compare, in the reference frame of the output.
+    pub(crate) fn error(
+        &self,
+        step: usize,
+        bodies: &[Body],
+    ) -> Option<Result<PositionError, String>> {
+        let frame = self.frames.get(&step)?;
+
+        let mut error = PositionError::default();
+        let mut squared_sum = 0f64;
+        for b in bodies {
+            let Some(position) = frame.get(&b.id) else {
+                return Some(Err(format!(
+                    "body {} is missing in the reference frame of step {}",
+                    b.id, step
+                )));
+            };
+            let distance = (b.position[0] - position[0]).hypot(b.position[1] - position[1]);
+            error.max = error.max.max(distance);
+            squared_sum += distance * distance;
+        }
+        error.rms = (squared_sum / bodies.len().max(1) as f64).sqrt();
+        Some(Ok(error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{CsvWriter, TrajectoryWriter};
+
+    fn trajectory(frames: &[Vec<Body>]) -> Vec<std::io::Result<String>> {
+        let mut bytes = Vec::new();
+        let mut writer = CsvWriter::new(&mut bytes, false).unwrap();
+        for (step, bodies) in frames.iter().enumerate() {
+            writer.write_frame(step, step as f64, bodies, None).unwrap();
+        }
+        let text = String::from_utf8(bytes).unwrap();
+        text.lines().map(|l| Ok(l.to_string())).collect()
+    }
+
+    #[test]
+    fn matching_reference_passes_and_perturbed_one_fails() {
+        let frames: Vec<Vec<Body>> = (0..3)
+            .map(|step| {
+                (0..4)
+                    .map(|id| Body {
+                        id,
+                        mass: 1f64,
+                        position: [id as f64 + 0.1 * step as f64, -(id as f64)],
+                        ..Body::default()
+                    })
+                    .collect()
+            })
+            .collect();
+        let reference = Reference::parse(trajectory(&frames)).unwrap();
+        for (step, bodies) in frames.iter().enumerate() {
+            let error = reference.error(step, bodies).unwrap().unwrap();
+            assert_eq!(error.max, 0f64);
+            assert_eq!(error.check(1e-9), Ok(()));
+        }
+        assert!(reference.error(3, &frames[0]).is_none());
+
+        // one of four bodies off by 0.3 m
+        let mut perturbed = frames[2].clone();
+        perturbed[1].position[1] += 0.3;
+        let error = reference.error(2, &perturbed).unwrap().unwrap();
+        assert!((error.max - 0.3).abs() < 1e-12, "{:?}", error);
+        assert!((error.rms - 0.15).abs() < 1e-12, "{:?}", error);
+        let message = error.check(0.1).unwrap_err();
+        assert!(
+            message.contains("exceeds the reference tolerance 0.1 m"),
+            "{}",
+            message
+        );
+        assert_eq!(error.check(0.5), Ok(()));
+    }
+}
@@ -0,0 +1,185 @@
+//! Rasterization of output frames to PNG images, e.g. for assembling a video.
+//!
+//! Every frame is written to its own file, see `output::frame_path`. Bodies are drawn as
+//! dots on black whose size and color (blue for light, red for heavy bodies) follow the
+//! square root of their mass relative to the heaviest body. The axes are fixed to a
+//! square around the bounds of the first rendered frame, so later frames are comparable.
+//!
+//! Encoding the images needs the `png` crate, which is only built with the `render`
+//! feature (`cargo build --release --features render`).
+
+use super::output::frame_path;
+use super::Body;
+
+/// Fraction of the first frame's extent added around it, so bodies on the edge are visible.
+const AXES_MARGIN: f64 = 0.05;
+
+/// Largest radius of a dot in pixels, the one of the heaviest body.
+const MAX_DOT_RADIUS: f64 = 4.0;
+
+const NO_PNG_SUPPORT: &str =
+    "n-body was built without PNG support, rebuild it with `--features render`";
+
+pub(crate) struct Renderer {
+    path: String,
+    size: u32,
+    /// Lower left corner and side length of the drawn square, fixed by the first frame
+    axes: Option<([f64; 2], f64)>,
+    /// RGB values of all pixels, row by row from the top
+    pixels: Vec<u8>,
+}
+
+impl Renderer {
+    /// Fails if the crate was built without the `render` feature.
+    ///
+    /// * `path`: Path of the images, the step of every frame is appended to its stem.
+    /// * `size`: Width and height of the images in pixels.
+    pub(crate) fn new(path: &str, size: u32) -> Result<Self, String> {
+        if !cfg!(feature = "render") {
+            return Err(NO_PNG_SUPPORT.to_string());
+        }
+        Ok(Renderer {
+            path: path.to_string(),
+            size,
+            axes: None,
+            pixels: Vec::new(),
+        })
+    }
+
+    /// Draw the bodies of one frame and write the image.
+    ///
+    /// * `step`: Step of the frame.
+    /// * `bodies`: All bodies of the frame.
+    pub(crate) fn render(&mut self, step: usize, bodies: &[Body]) -> Result<(), String> {
+        let (origin, side) = *self.axes.get_or_insert_with(|| axes_of(bodies));
+        let size = self.size as usize;
+        self.pixels.clear();
+        self.pixels.resize(size * size * 3, 0);
+
+        let max_mass = bodies.iter().map(|b| b.mass).fold(0f64, f64::max);
+        for b in bodies {
+            let weight = if max_mass > 0f64 {
+                (b.mass.max(0f64) / max_mass).sqrt()
+            } else {
+                0f64
+            };
+            let color = [
+                (255f64 * weight) as u8,
+                64,
+                (255f64 * (1f64 - weight)) as u8,
+            ];
+            let radius = 1f64 + (MAX_DOT_RADIUS - 1f64) * weight;
+
+            // pixel coordinates, y grows downwards in the image
+            let x = (b.position[0] - origin[0]) / side * size as f64;
+            let y = (1f64 - (b.position[1] - origin[1]) / side) * size as f64;
+            let reach = radius.ceil() as i64;
+            for py in y as i64 - reach..=y as i64 + reach {
+                for px in x as i64 - reach..=x as i64 + reach {
+                    let inside = (px as f64 + 0.5 - x).hypot(py as f64 + 0.5 - y) <= radius;
+                    if inside && (0..size as i64).contains(&px) && (0..size as i64).contains(&py) {
+                        let offset = (py as usize * size + px as usize) * 3;
+                        self.pixels[offset..offset + 3].copy_from_slice(&color);
+                    }
+                }
+            }
+        }
+
+        let path = frame_path(&self.path, step);
+        encode_png(&path, self.size, &self.pixels)
+            .map_err(|e| format!("Could not write {}: {}", path, e))
+    }
+}
+
+/// Lower left corner and side length of a square around the bounds of the bodies.
+///
+/// * `bodies`: Bodies to fit into the square.
+fn axes_of(bodies: &[Body]) -> ([f64; 2], f64) {
+    let mut bounds = [[f64::INFINITY, f64::NEG_INFINITY]; 2];
+    for b in bodies
+        .iter()
+        .filter(|b| b.position.iter().all(|p| p.is_finite()))
+    {
+        for (bound, position) in bounds.iter_mut().zip(b.position) {
+            *bound = [bound[0].min(position), bound[1].max(position)];
+        }
+    }
+    if !bounds.iter().all(|[min, max]| min <= max) {
+        return ([-0.5, -0.5], 1f64);
+    }
+
+    let extent = f64::max(bounds[0][1] - bounds[0][0], bounds[1][1] - bounds[1][0]);
+    // a single body or bodies on a line still get a visible square
+    let side = if extent > 0f64 { extent } else { 1f64 } * (1f64 + 2f64 * AXES_MARGIN);
+    let center = [0, 1].map(|dim| (bounds[dim][0] + bounds[dim][1]) / 2f64);
+    ([center[0] - side / 2f64, center[1] - side / 2f64], side)
+}
+
+#[cfg(feature = "render")]
+fn encode_png(path: &str, size: u32, pixels: &[u8]) -> Result<(), String> {
+    let file = std::fs::File::create(path).map_err(|e| e.to_string())?;
+    let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), size, size);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer.write_image_data(pixels).map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "render"))]
+fn encode_png(_path: &str, _size: u32, _pixels: &[u8]) -> Result<(), String> {
+    Err(NO_PNG_SUPPORT.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn two_bodies() -> [Body; 2] {
+        [(0, 1f64, [-1f64, 0f64]), (1, 16f64, [3f64, 2f64])].map(|(id, mass, position)| Body {
+            id,
+            mass,
+            position,
+            ..Body::default()
+        })
+    }
+
+    #[test]
+    fn axes_hold_all_bodies_with_a_margin() {
+        let (origin, side) = axes_of(&two_bodies());
+        assert!((side - 4.4).abs() < 1e-12, "{}", side);
+        assert!((origin[0] - -1.2).abs() < 1e-12 && (origin[1] - -1.2).abs() < 1e-12);
+        assert_eq!(axes_of(&[]), ([-0.5, -0.5], 1f64));
+    }
+
+    #[cfg(feature = "render")]
+    #[test]
+    fn two_body_frame_renders_to_a_png_of_the_requested_size() {
+        let path = std::env::temp_dir().join(format!("render_{}.png", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut renderer = Renderer::new(path, 48).unwrap();
+        renderer.render(3, &two_bodies()).unwrap();
+        let frame = frame_path(path, 3);
+        let file = std::fs::File::open(&frame).unwrap();
+        let mut reader = png::Decoder::new(file).read_info().unwrap();
+        let mut pixels = vec![0u8; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        std::fs::remove_file(&frame).unwrap();
+
+        assert_eq!((info.width, info.height), (48, 48));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        // both dots are drawn, the heavy one red and the light one blue
+        let colors: Vec<&[u8]> = pixels
+            .chunks(3)
+            .filter(|c| c.iter().any(|&v| v > 0))
+            .collect();
+        assert!(colors.iter().any(|c| c[0] == 255 && c[2] == 0));
+        assert!(colors.iter().any(|c| c[0] < c[2]));
+    }
+
+    #[cfg(not(feature = "render"))]
+    #[test]
+    fn rendering_needs_the_render_feature() {
+        assert!(Renderer::new("frame.png", 48).is_err());
+        assert!(encode_png("frame.png", 1, &[0; 3]).is_err());
+    }
+}
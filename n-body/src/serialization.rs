@@ -0,0 +1,401 @@
+//! Serialization of trees for the exchange between processes.
+//!
+//! Besides bitcode, a hand-rolled `compact` format is available. It makes use of the
+//! fact that centers and sizes of all children follow from their parent (see
+//! `TreeNode::split`) and that most children of a sparse tree are empty. Its layout is
+//!
+//! ```text
+//! tree:  center f64 f64, size f64, node
+//! node:  tag u8, followed by
+//!        0 (empty):  nothing
+//!        1 (body):   mass f64, mass_center f64 f64, body
+//!        2 (inner):  mass f64, mass_center f64 f64, child mask u8,
+//!                    one node for every set bit of the mask (child i = bit i)
+//!        3 (bucket): mass f64, mass_center f64 f64, body, count varint,
+//!                    count times body (see `TreeNode::bucket`)
+//! body:  id varint, mass f64, position f64 f64, velocity f64 f64, radius f64, tag u8
+//! ```
+//!
+//! All floats are little endian, varints use LEB128.
+
+use super::tree::{Coarsened, TreeNode};
+use super::Body;
+
+use clap::ValueEnum;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum TreeFormat {
+    Bitcode,
+    Compact,
+}
+
+const TAG_EMPTY: u8 = 0;
+const TAG_BODY: u8 = 1;
+const TAG_INNER: u8 = 2;
+const TAG_BUCKET: u8 = 3;
+
+/// Serialize a tree in the given format into a buffer, replacing its content.
+///
+/// The compact format reuses the buffer's allocation, bitcode's serde interface always
+/// returns a fresh one.
+///
+/// * `tree`: Tree to serialize.
+/// * `format`: Format to use.
+/// * `buf`: Buffer the serialized tree is written to.
+pub(crate) fn serialize_tree_into(tree: &TreeNode, format: TreeFormat, buf: &mut Vec<u8>) {
+    match format {
+        TreeFormat::Bitcode => *buf = bitcode::serialize(tree).unwrap(),
+        TreeFormat::Compact => write_compact(buf, tree, &|_| None),
+    }
+}
+
+/// Serialize a coarsened view of a tree like `serialize_tree_into` serializes the tree it
+/// stands for, so the receivers deserialize it as a plain tree.
+///
+/// * `view`: Coarsened view of the tree to serialize.
+/// * `format`: Format to use.
+/// * `buf`: Buffer the serialized tree is written to.
+pub(crate) fn serialize_coarsened_into(view: Coarsened, format: TreeFormat, buf: &mut Vec<u8>) {
+    match format {
+        TreeFormat::Bitcode => *buf = bitcode::serialize(&view).unwrap(),
+        TreeFormat::Compact => write_compact(buf, view.node, &|node| view.of(node).pseudo_body()),
+    }
+}
+
+/// * `pseudo_body`: Pseudo-body replacing a node and its subtree, if any.
+fn write_compact(
+    buf: &mut Vec<u8>,
+    tree: &TreeNode,
+    pseudo_body: &dyn Fn(&TreeNode) -> Option<Body>,
+) {
+    buf.clear();
+    write_f64(buf, tree.center[0]);
+    write_f64(buf, tree.center[1]);
+    write_f64(buf, tree.size);
+    write_node(buf, tree, pseudo_body);
+}
+
+/// Deserialize a tree in the given format.
+///
+/// * `bytes`: Serialized tree.
+/// * `format`: Format the tree was serialized in.
+pub(crate) fn deserialize_tree(bytes: &[u8], format: TreeFormat) -> Result<TreeNode, String> {
+    match format {
+        TreeFormat::Bitcode => bitcode::deserialize(bytes).map_err(|e| e.to_string()),
+        TreeFormat::Compact => {
+            let mut reader = Reader { bytes, offset: 0 };
+            let center = [reader.f64()?, reader.f64()?];
+            let size = reader.f64()?;
+            let tree = read_node(&mut reader, center, size)?;
+            if reader.offset != bytes.len() {
+                return Err(format!(
+                    "{} trailing bytes after tree",
+                    bytes.len() - reader.offset
+                ));
+            }
+            Ok(tree)
+        }
+    }
+}
+
+fn write_f64(buf: &mut Vec<u8>, value: f64) {
+    buf.extend(value.to_le_bytes());
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn is_empty(node: &TreeNode) -> bool {
+    node.body.is_none() && node.children.is_empty()
+}
+
+fn write_node(buf: &mut Vec<u8>, node: &TreeNode, pseudo_body: &dyn Fn(&TreeNode) -> Option<Body>) {
+    if is_empty(node) {
+        buf.push(TAG_EMPTY);
+        return;
+    }
+    if let Some(b) = pseudo_body(node) {
+        buf.push(TAG_BODY);
+        write_f64(buf, node.mass);
+        write_f64(buf, node.mass_center[0]);
+        write_f64(buf, node.mass_center[1]);
+        write_body(buf, &b);
+        return;
+    }
+
+    buf.push(if !node.bucket.is_empty() {
+        TAG_BUCKET
+    } else if node.body.is_some() {
+        TAG_BODY
+    } else {
+        TAG_INNER
+    });
+    write_f64(buf, node.mass);
+    write_f64(buf, node.mass_center[0]);
+    write_f64(buf, node.mass_center[1]);
+
+    if let Some(b) = &node.body {
+        write_body(buf, b);
+        if !node.bucket.is_empty() {
+            write_varint(buf, node.bucket.len() as u64);
+            for b in node.bucket.iter() {
+                write_body(buf, b);
+            }
+        }
+    } else {
+        let mask = node
+            .children
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| !is_empty(c))
+            .fold(0u8, |mask, (i, _)| mask | (1 << i));
+        buf.push(mask);
+        for child in node.children.iter().filter(|c| !is_empty(c)) {
+            write_node(buf, child, pseudo_body);
+        }
+    }
+}
+
+fn write_body(buf: &mut Vec<u8>, b: &Body) {
+    write_varint(buf, b.id as u64);
+    for value in [
+        b.mass,
+        b.position[0],
+        b.position[1],
+        b.velocity[0],
+        b.velocity[1],
+        b.radius,
+    ] {
+        write_f64(buf, value);
+    }
+    buf.push(b.tag);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Result<u8, String> {
+        let byte = *self
+            .bytes
+            .get(self.offset)
+            .ok_or("unexpected end of serialized tree")?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        let end = self.offset + 8;
+        let bytes = self
+            .bytes
+            .get(self.offset..end)
+            .ok_or("unexpected end of serialized tree")?;
+        self.offset = end;
+        Ok(f64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("varint too long".into())
+    }
+}
+
+fn read_node(reader: &mut Reader, center: [f64; 2], size: f64) -> Result<TreeNode, String> {
+    let mut node = TreeNode {
+        center,
+        size,
+        ..TreeNode::default()
+    };
+
+    let tag = reader.u8()?;
+    if tag == TAG_EMPTY {
+        return Ok(node);
+    }
+
+    node.mass = reader.f64()?;
+    node.mass_center = [reader.f64()?, reader.f64()?];
+
+    match tag {
+        TAG_BODY => {
+            node.body = Some(read_body(reader)?);
+        }
+        TAG_BUCKET => {
+            node.body = Some(read_body(reader)?);
+            let count = reader.varint()?;
+            for _ in 0..count {
+                node.bucket.push(read_body(reader)?);
+            }
+        }
+        TAG_INNER => {
+            let mask = reader.u8()?;
+            // creates the children with exactly the centers and sizes the sender had
+            node.split();
+            for (i, child) in node.children.iter_mut().enumerate() {
+                if mask & (1 << i) != 0 {
+                    *child = read_node(reader, child.center, child.size)?;
+                }
+            }
+        }
+        _ => return Err(format!("unknown node tag {}", tag)),
+    }
+
+    Ok(node)
+}
+
+fn read_body(reader: &mut Reader) -> Result<Body, String> {
+    Ok(Body {
+        id: reader.varint()? as usize,
+        mass: reader.f64()?,
+        position: [reader.f64()?, reader.f64()?],
+        velocity: [reader.f64()?, reader.f64()?],
+        radius: reader.f64()?,
+        tag: reader.u8()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::NodePool;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Tree of random bodies, with a bucket of coincident ones.
+    fn test_tree() -> TreeNode {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut root = TreeNode {
+            center: [1f64, -2f64],
+            size: 8f64,
+            ..TreeNode::default()
+        };
+        for id in 0..200 {
+            let body = Body {
+                id,
+                mass: rng.gen_range(1f64..10f64),
+                position: [rng.gen_range(-3f64..5f64), rng.gen_range(-6f64..2f64)],
+                velocity: [rng.gen(), rng.gen()],
+                radius: rng.gen(),
+                tag: rng.gen(),
+            };
+            root.insert(&body);
+        }
+        for id in 200..203 {
+            root.insert_pooled(
+                &Body {
+                    id,
+                    mass: 1f64,
+                    position: [0.5, 0.5],
+                    ..Body::default()
+                },
+                &mut NodePool::default(),
+                0.01,
+            );
+        }
+        root
+    }
+
+    fn has_bucket(node: &TreeNode) -> bool {
+        !node.bucket.is_empty() || node.children.iter().any(has_bucket)
+    }
+
+    #[test]
+    fn compact_format_round_trips() {
+        let tree = test_tree();
+        assert!(has_bucket(&tree));
+        let mut bytes = Vec::new();
+        serialize_tree_into(&tree, TreeFormat::Compact, &mut bytes);
+        let read = deserialize_tree(&bytes, TreeFormat::Compact).unwrap();
+
+        // bitcode writes every field of every node, so equal bytes mean equal trees
+        let mut expected = Vec::new();
+        let mut actual = Vec::new();
+        serialize_tree_into(&tree, TreeFormat::Bitcode, &mut expected);
+        serialize_tree_into(&read, TreeFormat::Bitcode, &mut actual);
+        assert_eq!(actual, expected);
+        assert!(bytes.len() < expected.len());
+    }
+
+    #[test]
+    fn truncated_compact_tree_is_rejected() {
+        let mut bytes = Vec::new();
+        serialize_tree_into(&test_tree(), TreeFormat::Compact, &mut bytes);
+        assert!(deserialize_tree(&bytes[..bytes.len() - 1], TreeFormat::Compact).is_err());
+        bytes.push(0);
+        assert!(deserialize_tree(&bytes, TreeFormat::Compact).is_err());
+    }
+
+    #[test]
+    fn reused_buffer_matches_fresh_serialization() {
+        let large = test_tree();
+        let small = TreeNode {
+            size: 2f64,
+            ..TreeNode::default()
+        };
+        for format in [TreeFormat::Bitcode, TreeFormat::Compact] {
+            let mut scratch = Vec::new();
+            for tree in [&large, &small, &large] {
+                serialize_tree_into(tree, format, &mut scratch);
+                let mut fresh = Vec::new();
+                serialize_tree_into(tree, format, &mut fresh);
+                assert_eq!(scratch, fresh, "{:?}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn coarsened_view_serializes_like_the_tree_it_stands_for() {
+        fn pseudo_bodies(node: &TreeNode) -> usize {
+            let own = node.body.as_ref().is_some_and(|b| b.id == usize::MAX) as usize;
+            own + node.children.iter().map(pseudo_bodies).sum::<usize>()
+        }
+        let tree = test_tree();
+        let regions = [[[4f64, 5f64], [1f64, 2f64]]];
+        let serialized = |view: Coarsened, format| {
+            let mut bytes = Vec::new();
+            serialize_coarsened_into(view, format, &mut bytes);
+            let read = deserialize_tree(&bytes, format).unwrap();
+            let mut canonical = Vec::new();
+            serialize_tree_into(&read, TreeFormat::Bitcode, &mut canonical);
+            (bytes, read, canonical)
+        };
+
+        // both formats hold the same coarsened tree
+        let (compact, read, canonical) =
+            serialized(tree.coarsened(1f64, &regions), TreeFormat::Compact);
+        let (bitcode, _, bitcode_canonical) =
+            serialized(tree.coarsened(1f64, &regions), TreeFormat::Bitcode);
+        assert_eq!(canonical, bitcode_canonical);
+        assert_eq!(bitcode, bitcode_canonical);
+        assert!(pseudo_bodies(&read) > 0);
+        assert!((read.mass - tree.mass).abs() < 1e-9 * tree.mass);
+        let mut full = Vec::new();
+        serialize_tree_into(&tree, TreeFormat::Compact, &mut full);
+        assert!(compact.len() < full.len());
+
+        // a view which replaces nothing serializes exactly like the tree
+        for format in [TreeFormat::Bitcode, TreeFormat::Compact] {
+            let (bytes, _, _) = serialized(tree.coarsened(0f64, &regions), format);
+            serialize_tree_into(&tree, format, &mut full);
+            assert_eq!(bytes, full, "{:?}", format);
+        }
+    }
+}
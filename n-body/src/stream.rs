@@ -0,0 +1,130 @@
+//! Live stream of the bodies' states over TCP for external viewers.
+//!
+//! The root listens on the given address. Every client receives the header of the chunked
+//! output format once after connecting and then one chunk per step, see `output.rs` for
+//! the layout. Clients that disconnect or stall are dropped without affecting the run.
+
+use super::output::{ChunkedWriter, TrajectoryWriter};
+use super::Body;
+
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+/// Maximum time a single frame may take to be sent to a client before it is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub(crate) struct PositionStream {
+    listener: TcpListener,
+    clients: Vec<ChunkedWriter<TcpStream>>,
+    n_bodies: usize,
+}
+
+impl PositionStream {
+    /// Start listening for viewers.
+    ///
+    /// * `addr`: Address to listen on, e.g. `localhost:7878`.
+    /// * `n_bodies`: Number of bodies every frame will contain.
+    pub(crate) fn bind(addr: &str, n_bodies: usize) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(PositionStream {
+            listener,
+            clients: Vec::new(),
+            n_bodies,
+        })
+    }
+
+    /// Accept all pending connections without blocking.
+    fn accept_clients(&mut self) {
+        while let Ok((stream, peer)) = self.listener.accept() {
+            let connected = stream
+                .set_nonblocking(false)
+                .and_then(|_| stream.set_write_timeout(Some(WRITE_TIMEOUT)))
+                .and_then(|_| stream.set_nodelay(true))
+                .and_then(|_| ChunkedWriter::new(stream, self.n_bodies, false));
+            match connected {
+                Ok(client) => {
+                    println!("Viewer {} connected", peer);
+                    self.clients.push(client);
+                }
+                Err(e) => println!("Could not set up viewer {}: {}", peer, e),
+            }
+        }
+    }
+
+    /// Send one frame to all connected viewers, dropping the ones that fail.
+    ///
+    /// * `step`: Number of the step the state belongs to.
+    /// * `time`: Simulated time of the state.
+    /// * `bodies`: All bodies of the system.
+    pub(crate) fn send(&mut self, step: usize, time: f64, bodies: &[Body]) {
+        self.accept_clients();
+        self.clients.retain_mut(
+            |client| match client.write_frame(step, time, bodies, None) {
+                Ok(()) => true,
+                Err(e) => {
+                    println!("Viewer disconnected: {}", e);
+                    false
+                }
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    fn test_bodies() -> Vec<Body> {
+        (0..3)
+            .map(|id| Body {
+                id,
+                mass: 1f64,
+                position: [id as f64, 2f64],
+                ..Body::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn loopback_client_receives_frame() {
+        let mut stream = PositionStream::bind("127.0.0.1:0", 3).unwrap();
+        let mut client = TcpStream::connect(stream.listener.local_addr().unwrap()).unwrap();
+        stream.send(5, 2.5, &test_bodies());
+        // closes the connection, so the client can read up to the end
+        drop(stream);
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+
+        let mut expected = Vec::new();
+        let mut writer = ChunkedWriter::new(&mut expected, 3, false).unwrap();
+        writer.write_frame(5, 2.5, &test_bodies(), None).unwrap();
+        drop(writer);
+        assert_eq!(received, expected);
+
+        let mut header = Vec::new();
+        ChunkedWriter::new(&mut header, 3, false).unwrap();
+        let chunk = &received[header.len()..];
+        let step = u64::from_le_bytes(chunk[8..16].try_into().unwrap());
+        let time = f64::from_le_bytes(chunk[16..24].try_into().unwrap());
+        assert_eq!((step, time), (5, 2.5));
+    }
+
+    #[test]
+    fn disconnected_client_is_dropped() {
+        let mut stream = PositionStream::bind("127.0.0.1:0", 3).unwrap();
+        let client = TcpStream::connect(stream.listener.local_addr().unwrap()).unwrap();
+        stream.send(0, 0f64, &test_bodies());
+        assert_eq!(stream.clients.len(), 1);
+
+        drop(client);
+        // the first writes after the disconnect may still succeed
+        for step in 1..100 {
+            stream.send(step, 0f64, &test_bodies());
+        }
+        assert!(stream.clients.is_empty());
+    }
+}
@@ -0,0 +1,204 @@
+//! Timeline of the phases of every step in Chrome's trace-event format.
+//!
+//! Every process records one complete event per phase and step, the root gathers them
+//! and writes a JSON file which can be opened in `chrome://tracing` or Perfetto, with
+//! one track per rank. Times are taken from `mpi::time()` relative to the start of the
+//! run, so the tracks of different ranks are only aligned as well as their clocks are.
+
+use super::{Timings, ROOT_RANK};
+
+use mpi::topology::SimpleCommunicator;
+use mpi::traits::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+const PHASES: [&str; 4] = ["build", "merge", "calc", "gather"];
+
+/// One phase of one step on one process.
+#[derive(Clone, Copy, Debug, Equivalence, Default)]
+struct TraceEvent {
+    /// First integration step of the tree step
+    step: u64,
+    /// Index into `PHASES`
+    phase: u8,
+    /// Seconds since the start of the run
+    start: f64,
+    /// Seconds
+    duration: f64,
+}
+
+/// Phase events recorded by one process.
+#[derive(Debug)]
+pub(crate) struct TraceRecorder {
+    /// Time of the start of the run
+    origin: f64,
+    events: Vec<TraceEvent>,
+}
+
+impl TraceRecorder {
+    /// * `origin`: Time of the start of the run as returned by `mpi::time()`.
+    pub(crate) fn new(origin: f64) -> Self {
+        TraceRecorder {
+            origin,
+            events: Vec::new(),
+        }
+    }
+
+    /// Record the phases of one tree step.
+    ///
+    /// The build, merge and calc phases are laid out back to back from `tree_start`, so
+    /// time spent in verbose output between them is not shown.
+    ///
+    /// * `step`: First integration step of the tree step.
+    /// * `tree_start`: Time at which `barnes_hut` was called.
+    /// * `timings`: Phase timings of the step.
+    /// * `gather_start`: Time at which the gather of the updated bodies started.
+    pub(crate) fn record_step(
+        &mut self,
+        step: usize,
+        tree_start: f64,
+        timings: &Timings,
+        gather_start: f64,
+    ) {
+        let mut start = tree_start - self.origin;
+        for (phase, duration) in [timings.build, timings.merge_phase(), timings.calc]
+            .into_iter()
+            .enumerate()
+        {
+            self.events.push(TraceEvent {
+                step: step as u64,
+                phase: phase as u8,
+                start,
+                duration,
+            });
+            start += duration;
+        }
+        self.events.push(TraceEvent {
+            step: step as u64,
+            phase: 3,
+            start: gather_start - self.origin,
+            duration: timings.gather,
+        });
+    }
+
+    /// Gather the events of all processes on the root, which writes them to `path`.
+    ///
+    /// Has to be called by all processes, which recorded the same number of steps.
+    ///
+    /// * `world`: MPI communicator.
+    /// * `path`: JSON file to write.
+    pub(crate) fn write(&self, world: &SimpleCommunicator, path: &str) -> std::io::Result<()> {
+        let root_proc = world.process_at_rank(ROOT_RANK as i32);
+        if world.rank() as usize != ROOT_RANK {
+            root_proc.gather_into(&self.events[..]);
+            return Ok(());
+        }
+
+        let n_proc = world.size() as usize;
+        let mut all_events = vec![TraceEvent::default(); n_proc * self.events.len()];
+        root_proc.gather_into_root(&self.events[..], &mut all_events[..]);
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_json(&mut writer, &all_events, n_proc)?;
+        writer.flush()
+    }
+}
+
+/// Write the events of all processes as a JSON trace.
+///
+/// * `writer`: Destination of the trace.
+/// * `all_events`: Events of all processes, the same number of them per process and
+///   ordered by rank.
+/// * `n_proc`: Number of processes.
+fn write_json(
+    writer: &mut impl Write,
+    all_events: &[TraceEvent],
+    n_proc: usize,
+) -> std::io::Result<()> {
+    writeln!(writer, "{{\"traceEvents\": [")?;
+    for rank in 0..n_proc {
+        write!(
+            writer,
+            "{{\"name\": \"thread_name\", \"ph\": \"M\", \"pid\": 0, \"tid\": {}, \
+             \"args\": {{\"name\": \"rank {}\"}}}}",
+            rank, rank
+        )?;
+        let last = rank + 1 == n_proc && all_events.is_empty();
+        writeln!(writer, "{}", if last { "" } else { "," })?;
+    }
+    for (i, event) in all_events.iter().enumerate() {
+        write!(
+            writer,
+            "{{\"name\": \"{}\", \"ph\": \"X\", \"pid\": 0, \"tid\": {}, \"ts\": {}, \
+             \"dur\": {}, \"args\": {{\"step\": {}}}}}",
+            PHASES[event.phase as usize],
+            i * n_proc / all_events.len(),
+            event.start * 1e6,
+            event.duration * 1e6,
+            event.step
+        )?;
+        writeln!(
+            writer,
+            "{}",
+            if i + 1 < all_events.len() { "," } else { "" }
+        )?;
+    }
+    writeln!(writer, "]}}")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_has_one_event_per_phase_step_and_rank() {
+        let mut recorder = TraceRecorder::new(10f64);
+        let timings = Timings {
+            build: 0.5,
+            calc: 1f64,
+            gather: 0.25,
+            ..Timings::default()
+        };
+        for step in 0..3 {
+            let start = 10f64 + step as f64 * 2f64;
+            recorder.record_step(step, start, &timings, start + 1.5);
+        }
+        let n_proc = 2;
+        let all_events = recorder.events.repeat(n_proc);
+
+        let mut bytes = Vec::new();
+        write_json(&mut bytes, &all_events, n_proc).unwrap();
+        let text = String::from_utf8(bytes).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.first(), Some(&"{\"traceEvents\": ["));
+        assert_eq!(lines.last(), Some(&"]}"));
+
+        // every event is an object on its own line, separated by commas
+        let events = &lines[1..lines.len() - 1];
+        assert_eq!(events.len(), n_proc + 3 * PHASES.len() * n_proc);
+        for (i, event) in events.iter().enumerate() {
+            let object = match event.strip_suffix(',') {
+                Some(object) => object,
+                None => {
+                    assert_eq!(i, events.len() - 1);
+                    event
+                }
+            };
+            assert!(
+                object.starts_with('{') && object.ends_with('}'),
+                "{}",
+                object
+            );
+            assert_eq!(object.matches('{').count(), object.matches('}').count());
+        }
+        let complete = events
+            .iter()
+            .filter(|e| e.contains("\"ph\": \"X\""))
+            .count();
+        assert_eq!(complete, 3 * PHASES.len() * n_proc);
+        assert!(events[n_proc].contains("\"name\": \"build\""));
+        assert!(events[n_proc].contains("\"ts\": 0,") && events[n_proc].contains("\"tid\": 0,"));
+        assert!(events.last().unwrap().contains("\"tid\": 1,"));
+    }
+}
@@ -1,8 +1,169 @@
 use super::Body;
 use super::G;
 
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 
+/// Power of the ratio of reference to node density by which the adaptive opening
+/// criterion scales theta, e.g. a node 16 times denser than the reference is opened
+/// with theta / 2.
+const ADAPTIVE_THETA_EXPONENT: f64 = 0.25;
+
+/// Depth below the root at which `--safe-mode` stops splitting leaves.
+pub(crate) const SAFE_MODE_MAX_DEPTH: i32 = 40;
+
+/// Force law used within the softening length.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum SofteningKernel {
+    /// `r / (r^2 + eps^2)^(3/2)`, slightly weaker than Newtonian at all distances
+    Plummer,
+    /// Cubic spline kernel of GADGET, exactly Newtonian beyond the softening length
+    Spline,
+}
+
+/// Softening of the force between close masses.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Softening {
+    pub(crate) kernel: SofteningKernel,
+    /// Plummer `eps` or the radius `h` beyond which the spline kernel is Newtonian
+    pub(crate) length: f64,
+}
+
+impl Softening {
+    /// Factor `f(r)` of the force `G m M f(r) d` between two masses at the displacement
+    /// `d` with length `r`, `1 / r^3` without softening.
+    ///
+    /// * `distance`: Distance `r` of the masses.
+    fn force_factor(&self, distance: f64) -> f64 {
+        match self.kernel {
+            SofteningKernel::Plummer => {
+                (distance * distance + self.length * self.length).powf(-1.5)
+            }
+            SofteningKernel::Spline => {
+                let u = distance / self.length;
+                let h_inv3 = self.length.powi(-3);
+                if u < 0.5 {
+                    h_inv3 * (32f64 / 3f64 + u * u * (32f64 * u - 38.4))
+                } else if u < 1f64 {
+                    h_inv3
+                        * (64f64 / 3f64 - 48f64 * u + 38.4 * u * u
+                            - 32f64 / 3f64 * u * u * u
+                            - 1f64 / (15f64 * u * u * u))
+                } else {
+                    distance.powi(-3)
+                }
+            }
+        }
+    }
+
+    /// Factor `f(r)` of the potential energy `-G m M f(r)` of two masses, consistent with
+    /// `force_factor`, `1 / r` without softening.
+    ///
+    /// * `distance`: Distance `r` of the masses.
+    fn potential_factor(&self, distance: f64) -> f64 {
+        match self.kernel {
+            SofteningKernel::Plummer => {
+                (distance * distance + self.length * self.length).powf(-0.5)
+            }
+            SofteningKernel::Spline => {
+                let u = distance / self.length;
+                let h_inv = 1f64 / self.length;
+                if u < 0.5 {
+                    h_inv * (2.8 - u * u * (16f64 / 3f64 + u * u * (6.4 * u - 9.6)))
+                } else if u < 1f64 {
+                    h_inv
+                        * (3.2
+                            - 1f64 / (15f64 * u)
+                            - u * u * (32f64 / 3f64 + u * (-16f64 + u * (9.6 - 32f64 / 15f64 * u))))
+                } else {
+                    1f64 / distance
+                }
+            }
+        }
+    }
+}
+
+/// Potential energy of two masses at the given distance.
+///
+/// Without softening, masses closer than 1e-10 are treated as coinciding and contribute
+/// nothing instead of an infinite energy.
+///
+/// * `masses`: Product of the two masses.
+/// * `distance`: Distance of the masses.
+/// * `softening`: Softening of the force, Newtonian if `None`.
+pub(crate) fn pair_potential(masses: f64, distance: f64, softening: Option<&Softening>) -> f64 {
+    match softening {
+        Some(s) => -G * masses * s.potential_factor(distance),
+        None if distance < 1e-10f64 => 0f64,
+        None => -G * masses / distance,
+    }
+}
+
+/// Force between two masses at the given displacement.
+///
+/// * `masses`: Product of the two masses.
+/// * `displacement`: Vector from the mass the force acts on to the other one.
+/// * `distance`: Length of `displacement`.
+/// * `softening`: Softening of the force, Newtonian if `None`.
+fn pair_force(
+    masses: f64,
+    displacement: &[f64; 2],
+    distance: f64,
+    softening: Option<&Softening>,
+) -> [f64; 2] {
+    let factor = match softening {
+        Some(s) => s.force_factor(distance),
+        None => 1f64 / (distance * distance * distance),
+    };
+    let f = G * masses * factor;
+    [f * displacement[0], f * displacement[1]]
+}
+
+/// Children vectors of dropped trees, handed out again when nodes are split, so that
+/// rebuilding the tree every step reuses their allocations.
+#[derive(Debug, Default)]
+pub(crate) struct NodePool {
+    free: Vec<Vec<TreeNode>>,
+    /// Number of vectors handed out since the last reclaim
+    taken: usize,
+    /// Most vectors handed out between two reclaims, the pool keeps no more than that
+    peak: usize,
+}
+
+impl NodePool {
+    /// Take the children vectors of all nodes below the given one, leaving it a leaf.
+    /// Vectors beyond the most ever handed out between two reclaims are dropped, so that
+    /// reclaiming trees larger than the built ones, e.g. merged ones, does not grow the pool.
+    ///
+    /// * `node`: Root of the tree to take apart.
+    pub(crate) fn reclaim(&mut self, node: &mut TreeNode) {
+        self.peak = self.peak.max(self.taken);
+        self.taken = 0;
+        self.reclaim_node(node);
+    }
+
+    fn reclaim_node(&mut self, node: &mut TreeNode) {
+        if node.children.capacity() == 0 || self.free.len() >= self.peak {
+            node.children = Vec::new();
+            return;
+        }
+        for child in node.children.iter_mut() {
+            self.reclaim_node(child);
+        }
+        let mut children = std::mem::take(&mut node.children);
+        children.clear();
+        if self.free.len() < self.peak {
+            self.free.push(children);
+        }
+    }
+
+    /// An empty children vector, reused if the pool has one.
+    fn take(&mut self) -> Vec<TreeNode> {
+        self.taken += 1;
+        self.free.pop().unwrap_or_else(|| Vec::with_capacity(4))
+    }
+}
+
 #[derive(Clone, Default, Debug, Deserialize, Serialize)]
 pub(crate) struct TreeNode {
     pub(crate) center: [f64; 2],
@@ -11,12 +172,23 @@ pub(crate) struct TreeNode {
     pub(crate) mass_center: [f64; 2],
     pub(crate) children: Vec<TreeNode>,
     pub(crate) body: Option<Body>,
+    /// Further bodies of a leaf that can not be split any more, e.g. coincident ones.
+    /// Their forces are summed directly.
+    pub(crate) bucket: Vec<Body>,
 }
 
 impl TreeNode {
     /// Creates four subtrees as children for self.
     /// Each child represents one quadrant of the original tree span.
     pub(crate) fn split(&mut self) {
+        self.split_pooled(&mut NodePool::default());
+    }
+
+    /// Like `split`, but the children vector is taken from the pool.
+    ///
+    /// * `pool`: Source of the children vector.
+    pub(crate) fn split_pooled(&mut self, pool: &mut NodePool) {
+        self.children = pool.take();
         let center_offset = self.size / 4_f64;
         let mut dummy = TreeNode {
             size: self.size / 2_f64,
@@ -47,41 +219,70 @@ impl TreeNode {
     /// Push a given body down the hierarchy. If no chlidren exist yet, creates them.
     ///
     /// * `body`: Reference to a Body object.
-    pub(crate) fn push_to_child(&mut self, body: &Body) {
+    /// * `pool`: Source of the children vectors of split nodes.
+    /// * `min_size`: Size below which leaves are not split, see `insert_pooled`.
+    pub(crate) fn push_to_child(&mut self, body: &Body, pool: &mut NodePool, min_size: f64) {
         if self.children.is_empty() {
-            self.split();
+            self.split_pooled(pool);
         }
 
         if body.position[0] > self.center[0] {
             if body.position[1] > self.center[1] {
-                self.children[0].insert(body);
+                self.children[0].insert_pooled(body, pool, min_size);
             } else {
-                self.children[3].insert(body);
+                self.children[3].insert_pooled(body, pool, min_size);
             }
         } else if body.position[1] > self.center[1] {
-            self.children[1].insert(body);
+            self.children[1].insert_pooled(body, pool, min_size);
         } else {
-            self.children[2].insert(body);
+            self.children[2].insert_pooled(body, pool, min_size);
         }
     }
 
+    /// Whether splitting self can still separate bodies, i.e. self is larger than
+    /// `min_size` and the centers of its children differ from its own in floating point.
+    ///
+    /// * `min_size`: Size below which leaves are not split.
+    fn can_split(&self, min_size: f64) -> bool {
+        let offset = self.size / 4f64;
+        self.size > min_size
+            && self
+                .center
+                .iter()
+                .all(|&c| c + offset != c && c - offset != c)
+    }
+
     /// Insert a Body into the tree. The following three cases must be handled:
     ///
     /// 1. self is an empty leaf -> simply assign the body to self.body
     /// 2. self is a body leaf -> push down the existing and the given body
     /// 3. self has children already -> push down the given body
     ///
+    /// A body leaf which can not be split any more keeps further bodies in its bucket.
+    ///
     /// * `body`: reference to the body to be inserted
     pub(crate) fn insert(&mut self, body: &Body) {
+        self.insert_pooled(body, &mut NodePool::default(), 0f64);
+    }
+
+    /// Like `insert`, but split nodes take their children vectors from the pool.
+    ///
+    /// * `body`: reference to the body to be inserted
+    /// * `pool`: Source of the children vectors of split nodes.
+    /// * `min_size`: Size below which leaves are not split but collect their bodies in a
+    ///   bucket, which caps the depth of the tree.
+    pub(crate) fn insert_pooled(&mut self, body: &Body, pool: &mut NodePool, min_size: f64) {
         if self.children.is_empty() && self.body.is_none() {
             self.body = Some(body.clone());
+        } else if self.children.is_empty() && !self.can_split(min_size) {
+            self.bucket.push(body.clone());
         } else {
             if let Some(b) = &self.body {
-                self.push_to_child(&b.clone());
+                self.push_to_child(&b.clone(), pool, min_size);
                 self.body = None;
             }
 
-            self.push_to_child(body);
+            self.push_to_child(body, pool, min_size);
         }
 
         // update the node's mass and mass_center
@@ -100,12 +301,102 @@ impl TreeNode {
     /// they are far enough away form each other, self.mass and self.mass_center are
     /// are used for the force calculation which is the central point of Barnes-Hut.
     ///
+    /// A leaf holding the body itself (same `id`) contributes no force.
+    ///
+    /// * `body`: The body to calculate the force to.
+    /// * `theta`: Threshold ratio parameter for shortcutting the calculation.
+    /// * `softening`: Softening of the force, Newtonian if `None`.
+    pub(crate) fn calculate_force(
+        &self,
+        body: &Body,
+        theta: f64,
+        softening: Option<&Softening>,
+    ) -> [f64; 2] {
+        self.force_on(
+            &body.position,
+            body.mass,
+            Some(body.id),
+            theta,
+            None,
+            softening,
+        )
+    }
+
+    /// Like `calculate_force`, but nodes denser than self on average are opened with a
+    /// smaller theta, so dense cores are resolved more accurately while sparse regions
+    /// stay cheap. Theta is never increased.
+    ///
+    /// Has to be called on the root, whose mean density is the reference.
+    ///
     /// * `body`: The body to calculate the force to.
+    /// * `theta`: Threshold ratio parameter for nodes of at most the reference density.
+    /// * `softening`: Softening of the force, Newtonian if `None`.
+    pub(crate) fn calculate_force_adaptive(
+        &self,
+        body: &Body,
+        theta: f64,
+        softening: Option<&Softening>,
+    ) -> [f64; 2] {
+        let reference_density = self.mass / (self.size * self.size);
+        self.force_on(
+            &body.position,
+            body.mass,
+            Some(body.id),
+            theta,
+            Some(reference_density),
+            softening,
+        )
+    }
+
+    /// Recursively calculate the force on a unit mass at the given position, i.e. the
+    /// gravitational field of self there.
+    ///
+    /// Unlike `calculate_force` no body is excluded, as a probe is never part of the tree.
+    ///
+    /// * `position`: Position to evaluate the field at.
+    /// * `theta`: Threshold ratio parameter for shortcutting the calculation.
+    /// * `softening`: Softening of the force, Newtonian if `None`.
+    pub(crate) fn force_at(
+        &self,
+        position: &[f64; 2],
+        theta: f64,
+        softening: Option<&Softening>,
+    ) -> [f64; 2] {
+        self.force_on(position, 1f64, None, theta, None, softening)
+    }
+
+    /// Traversal shared by `calculate_force` and `force_at`.
+    ///
+    /// * `position`: Position of the mass the force acts on.
+    /// * `mass`: Mass the force acts on.
+    /// * `exclude`: Id of a body which exerts no force, the one the force acts on.
     /// * `theta`: Threshold ratio parameter for shortcutting the calculation.
-    pub(crate) fn calculate_force(&self, body: &Body, theta: f64) -> [f64; 2] {
+    /// * `reference_density`: Mean density of the root if the opening criterion adapts
+    ///   to the density of the nodes.
+    /// * `softening`: Softening of the force, Newtonian if `None`.
+    fn force_on(
+        &self,
+        position: &[f64; 2],
+        mass: f64,
+        exclude: Option<usize>,
+        theta: f64,
+        reference_density: Option<f64>,
+        softening: Option<&Softening>,
+    ) -> [f64; 2] {
+        if !self.bucket.is_empty() {
+            return self.bucket_force(position, mass, exclude, softening);
+        }
+
+        // a body must never exert a force on itself, even if its position got corrupted
+        if let Some(b) = &self.body {
+            if Some(b.id) == exclude {
+                return [0f64; 2];
+            }
+        }
+
         let displacement = [
-            self.mass_center[0] - body.position[0],
-            self.mass_center[1] - body.position[1],
+            self.mass_center[0] - position[0],
+            self.mass_center[1] - position[1],
         ];
         let distance =
             (displacement[0] * displacement[0] + displacement[1] * displacement[1]).sqrt();
@@ -116,22 +407,21 @@ impl TreeNode {
         }
 
         if let Some(b) = &self.body {
-            let f = G * b.mass * body.mass / (distance * distance);
-            [
-                f + displacement[0] / distance,
-                f + displacement[1] / distance,
-            ]
+            pair_force(b.mass * mass, &displacement, distance, softening)
         } else if !self.children.is_empty() {
-            if self.size / distance < theta {
-                let f = G * self.mass * body.mass / (distance * distance);
-                [
-                    f + displacement[0] / distance,
-                    f + displacement[1] / distance,
-                ]
+            if self.size / distance < self.opening_theta(theta, reference_density) {
+                pair_force(self.mass * mass, &displacement, distance, softening)
             } else {
                 let mut summed_force = [f64::default(); 2];
                 for child in self.children.iter() {
-                    let f = child.calculate_force(body, theta);
+                    let f = child.force_on(
+                        position,
+                        mass,
+                        exclude,
+                        theta,
+                        reference_density,
+                        softening,
+                    );
                     summed_force[0] += f[0];
                     summed_force[1] += f[1];
                 }
@@ -144,17 +434,132 @@ impl TreeNode {
         }
     }
 
+    /// Force of the bodies of a leaf with a bucket, summed directly.
+    ///
+    /// * `position`: Position of the mass the force acts on.
+    /// * `mass`: Mass the force acts on.
+    /// * `exclude`: Id of a body which exerts no force, the one the force acts on.
+    /// * `softening`: Softening of the force, Newtonian if `None`.
+    fn bucket_force(
+        &self,
+        position: &[f64; 2],
+        mass: f64,
+        exclude: Option<usize>,
+        softening: Option<&Softening>,
+    ) -> [f64; 2] {
+        let mut summed_force = [0f64; 2];
+        for b in self.body.iter().chain(&self.bucket) {
+            if Some(b.id) == exclude {
+                continue;
+            }
+            let displacement = [b.position[0] - position[0], b.position[1] - position[1]];
+            let distance = displacement[0].hypot(displacement[1]);
+            if distance < 1e-10f64 {
+                continue;
+            }
+            let f = pair_force(b.mass * mass, &displacement, distance, softening);
+            summed_force[0] += f[0];
+            summed_force[1] += f[1];
+        }
+        summed_force
+    }
+
+    /// Theta of the opening criterion of self, scaled down by
+    /// `(reference_density / density) ^ ADAPTIVE_THETA_EXPONENT` if self is denser than
+    /// the reference.
+    ///
+    /// * `theta`: Threshold ratio parameter for shortcutting the calculation.
+    /// * `reference_density`: Mean density of the root, `None` for a fixed theta.
+    fn opening_theta(&self, theta: f64, reference_density: Option<f64>) -> f64 {
+        match reference_density {
+            Some(reference) => {
+                let density = self.mass / (self.size * self.size);
+                theta
+                    * (reference / density)
+                        .powf(ADAPTIVE_THETA_EXPONENT)
+                        .min(1f64)
+            }
+            None => theta,
+        }
+    }
+
+    /// Recursively calculate the potential energy of the given body in the field of self.
+    ///
+    /// Uses the same opening criterion as `calculate_force`, so distant nodes are
+    /// approximated by their mass and mass_center.
+    ///
+    /// * `body`: The body to calculate the potential energy of.
+    /// * `theta`: Threshold ratio parameter for shortcutting the calculation.
+    /// * `softening`: Softening of the force, Newtonian if `None`.
+    pub(crate) fn calculate_potential(
+        &self,
+        body: &Body,
+        theta: f64,
+        softening: Option<&Softening>,
+    ) -> f64 {
+        if !self.bucket.is_empty() {
+            return self
+                .body
+                .iter()
+                .chain(&self.bucket)
+                .filter(|b| b.id != body.id)
+                .map(|b| {
+                    let distance = f64::hypot(
+                        b.position[0] - body.position[0],
+                        b.position[1] - body.position[1],
+                    );
+                    pair_potential(b.mass * body.mass, distance, softening)
+                })
+                .sum();
+        }
+
+        if let Some(b) = &self.body {
+            if b.id == body.id {
+                return 0f64;
+            }
+        }
+
+        let displacement = [
+            self.mass_center[0] - body.position[0],
+            self.mass_center[1] - body.position[1],
+        ];
+        let distance =
+            (displacement[0] * displacement[0] + displacement[1] * displacement[1]).sqrt();
+
+        if let Some(b) = &self.body {
+            pair_potential(b.mass * body.mass, distance, softening)
+        } else if !self.children.is_empty() {
+            if self.size / distance < theta {
+                pair_potential(self.mass * body.mass, distance, softening)
+            } else {
+                self.children
+                    .iter()
+                    .map(|c| c.calculate_potential(body, theta, softening))
+                    .sum()
+            }
+        } else {
+            0f64
+        }
+    }
+
     /// Merge to trees, consuming the given tree.
     ///
     /// * `other`: Another tree to be merged into self.
-    pub(crate) fn merge(&mut self, mut other: TreeNode) {
+    /// * `min_size`: Size below which leaves are not split when bodies are moved between
+    ///   the trees, see `insert_pooled`, so that merging keeps the depth cap of trees
+    ///   built with it.
+    pub(crate) fn merge(&mut self, mut other: TreeNode, min_size: f64) {
         // this assumes that two trees with the same size and center get merged
         assert!(self.size == other.size);
         assert!(self.center == other.center);
 
+        let mut pool = NodePool::default();
         if let Some(body) = &self.body {
-            // 1. case: self is single body
-            other.insert(body);
+            // 1. case: self is single body, maybe with a bucket
+            other.insert_pooled(body, &mut pool, min_size);
+            for b in self.bucket.iter() {
+                other.insert_pooled(b, &mut pool, min_size);
+            }
             *self = other;
         } else if self.children.is_empty() {
             // 2. case: self is empty
@@ -163,14 +568,15 @@ impl TreeNode {
             // 3. case: self has children
             // needs to handle three cases for other
             if let Some(b) = &other.body {
-                self.insert(b);
+                self.insert_pooled(b, &mut pool, min_size);
+                for b in other.bucket.iter() {
+                    self.insert_pooled(b, &mut pool, min_size);
+                }
             } else if other.children.is_empty() {
                 // empty case, other is empty quadrant and nothing to do here...
             } else {
-                for (self_child, other_child) in
-                    self.children.iter_mut().zip(other.children.into_iter())
-                {
-                    self_child.merge(other_child);
+                for (self_child, other_child) in self.children.iter_mut().zip(other.children) {
+                    self_child.merge(other_child, min_size);
                 }
 
                 self.mass = self.children.iter().map(|c| c.mass).sum();
@@ -190,11 +596,578 @@ impl TreeNode {
         }
     }
 
+    /// Recompute mass and mass_center of all nodes from the leaves upwards, always
+    /// summing the children in quadrant order and the bodies of a bucket leaf in id order.
+    ///
+    /// This makes the values, and the order in which a bucket's bodies exert their
+    /// forces, independent of the order in which bodies were inserted and trees were
+    /// merged, at the cost of a full traversal of the tree.
+    pub(crate) fn recompute_mass_centers(&mut self) {
+        if self.children.is_empty() {
+            self.sort_bucket();
+            return;
+        }
+
+        for child in self.children.iter_mut() {
+            child.recompute_mass_centers();
+        }
+
+        self.mass = self.children.iter().map(|c| c.mass).sum();
+        if self.mass > 0f64 {
+            self.mass_center[0] = self
+                .children
+                .iter()
+                .map(|c| c.mass_center[0] * c.mass)
+                .sum::<f64>()
+                / self.mass;
+            self.mass_center[1] = self
+                .children
+                .iter()
+                .map(|c| c.mass_center[1] * c.mass)
+                .sum::<f64>()
+                / self.mass;
+        }
+    }
+
+    /// Put the bodies of a leaf in id order, the lowest one as its body and the others in
+    /// its bucket, and recompute its mass and mass_center in that order.
+    fn sort_bucket(&mut self) {
+        let mut bodies: Vec<Body> = self.body.take().into_iter().collect();
+        bodies.append(&mut self.bucket);
+        if bodies.is_empty() {
+            return;
+        }
+        bodies.sort_by_key(|b| b.id);
+
+        self.mass = bodies.iter().map(|b| b.mass).sum();
+        for dim in 0..2 {
+            self.mass_center[dim] =
+                bodies.iter().map(|b| b.position[dim] * b.mass).sum::<f64>() / self.mass;
+        }
+        let mut bodies = bodies.into_iter();
+        self.body = bodies.next();
+        self.bucket.extend(bodies);
+    }
+
+    /// View of self in which every inner node that is far from all given regions is
+    /// replaced by a leaf holding a pseudo-body with its mass at its mass center. A node
+    /// is far from a region if `size / distance < theta` for the distance of its mass
+    /// center to the region, so the receivers of the view would not open it anyway.
+    ///
+    /// * `theta`: Threshold ratio below which a node is replaced.
+    /// * `regions`: Bounds of the bodies the forces of the view will be calculated for.
+    pub(crate) fn coarsened<'a>(
+        &'a self,
+        theta: f64,
+        regions: &'a [[[f64; 2]; 2]],
+    ) -> Coarsened<'a> {
+        Coarsened {
+            node: self,
+            theta,
+            regions,
+        }
+    }
+
+    /// Count all nodes of the tree, including self.
+    pub(crate) fn node_count(&self) -> usize {
+        1 + self.children.iter().map(|c| c.node_count()).sum::<usize>()
+    }
+
     pub(crate) fn height(&self) -> usize {
-        return if self.children.is_empty() {
+        if self.children.is_empty() {
             1
         } else {
             1 + self.children.iter().map(|c| c.height()).max().unwrap()
+        }
+    }
+}
+
+/// View of a tree with the distant subtrees replaced by pseudo-bodies, see
+/// `TreeNode::coarsened`. It serializes like the tree it stands for, so sending it does not
+/// need a copy of the tree.
+///
+/// Pseudo-bodies have the id `usize::MAX`, so they never match a real body.
+#[derive(Clone, Copy)]
+pub(crate) struct Coarsened<'a> {
+    pub(crate) node: &'a TreeNode,
+    theta: f64,
+    regions: &'a [[[f64; 2]; 2]],
+}
+
+impl<'a> Coarsened<'a> {
+    /// The pseudo-body replacing the node, if it is an inner node far from all regions.
+    pub(crate) fn pseudo_body(&self) -> Option<Body> {
+        let node = self.node;
+        if node.children.is_empty() {
+            return None;
+        }
+        let far = self.regions.iter().all(|region| {
+            let offset = [0, 1].map(|dim| {
+                (region[dim][0] - node.mass_center[dim])
+                    .max(node.mass_center[dim] - region[dim][1])
+                    .max(0f64)
+            });
+            node.size / offset[0].hypot(offset[1]) < self.theta
+        });
+        far.then_some(Body {
+            id: usize::MAX,
+            mass: node.mass,
+            position: node.mass_center,
+            velocity: [0f64; 2],
+            radius: 0f64,
+            tag: 0,
+        })
+    }
+
+    /// View of another node of the tree, coarsened in the same way.
+    ///
+    /// * `node`: Node to view, e.g. a child.
+    pub(crate) fn of(&self, node: &'a TreeNode) -> Coarsened<'a> {
+        Coarsened { node, ..*self }
+    }
+}
+
+impl Serialize for Coarsened<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        /// Children of a node which is not replaced.
+        struct Children<'a>(Coarsened<'a>);
+
+        impl Serialize for Children<'_> {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_seq(self.0.node.children.iter().map(|c| self.0.of(c)))
+            }
+        }
+
+        // the same fields in the same order as the derived implementation of TreeNode
+        let node = self.node;
+        let pseudo_body = self.pseudo_body();
+        let mut state = serializer.serialize_struct("TreeNode", 7)?;
+        state.serialize_field("center", &node.center)?;
+        state.serialize_field("size", &node.size)?;
+        state.serialize_field("mass", &node.mass)?;
+        state.serialize_field("mass_center", &node.mass_center)?;
+        if pseudo_body.is_some() {
+            state.serialize_field("children", &Vec::<TreeNode>::new())?;
+            state.serialize_field("body", &pseudo_body)?;
+        } else {
+            state.serialize_field("children", &Children(*self))?;
+            state.serialize_field("body", &node.body)?;
+        }
+        state.serialize_field("bucket", &node.bucket)?;
+        state.end()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::partition::sort_by_morton;
+    use crate::serialization::{
+        deserialize_tree, serialize_coarsened_into, serialize_tree_into, TreeFormat,
+    };
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    pub(crate) fn body(id: usize, mass: f64, position: [f64; 2]) -> Body {
+        Body {
+            id,
+            mass,
+            position,
+            ..Body::default()
+        }
+    }
+
+    /// Tree of the given bodies with a root square of side 8 about the origin.
+    pub(crate) fn tree_of(bodies: &[Body]) -> TreeNode {
+        let mut root = TreeNode {
+            size: 8f64,
+            ..TreeNode::default()
+        };
+        for b in bodies {
+            root.insert(b);
+        }
+        root
+    }
+
+    /// Bodies with random masses at random positions within the root of `tree_of`.
+    pub(crate) fn random_bodies(n: usize, seed: u64) -> Vec<Body> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..n)
+            .map(|id| {
+                let position = [rng.gen_range(-4f64..4f64), rng.gen_range(-4f64..4f64)];
+                body(id, rng.gen_range(1f64..1e3), position)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn single_body_feels_no_force() {
+        let b = body(0, 5f64, [1f64, 1f64]);
+        let tree = tree_of(std::slice::from_ref(&b));
+        assert_eq!(tree.calculate_force(&b, 0.5, None), [0f64; 2]);
+    }
+
+    #[test]
+    fn body_is_excluded_by_id_even_if_moved() {
+        // the tree still holds the old position, the body must not attract itself
+        let b = body(0, 5f64, [1f64, 1f64]);
+        let tree = tree_of(std::slice::from_ref(&b));
+        let moved = body(0, 5f64, [1.5, 1f64]);
+        assert_eq!(tree.calculate_force(&moved, 0.5, None), [0f64; 2]);
+    }
+
+    #[test]
+    fn pair_forces_are_opposite() {
+        let bodies = [body(0, 1e3, [-1f64, 0f64]), body(1, 2e3, [2f64, 0f64])];
+        let tree = tree_of(&bodies);
+        let f0 = tree.calculate_force(&bodies[0], 0.5, None);
+        let f1 = tree.calculate_force(&bodies[1], 0.5, None);
+        let expected = G * 1e3 * 2e3 / 9f64;
+        assert!((f0[0] - expected).abs() < 1e-12 * expected);
+        assert_eq!(f0[1], 0f64);
+        assert_eq!(f1, [-f0[0], -f0[1]]);
+    }
+
+    #[test]
+    fn recomputed_mass_centers_do_not_depend_on_merge_order() {
+        let mut bodies = random_bodies(200, 1);
+        // coincident bodies share a bucket leaf, a merge puts the bodies of the merged tree
+        // first, so it is filled in the reverse order of the parts
+        let at = bodies[0].position;
+        bodies.extend([(200, 1.1), (201, 2.3), (202, 3.7)].map(|(id, m)| body(id, m, at)));
+        bodies.swap(100, 201);
+        let mut whole = tree_of(&bodies);
+        let mut merged = tree_of(&[]);
+        for part in bodies.chunks(50) {
+            merged.merge(tree_of(part), 0f64);
+        }
+        whole.recompute_mass_centers();
+        merged.recompute_mass_centers();
+
+        assert_eq!(whole.mass.to_bits(), merged.mass.to_bits());
+        assert_eq!(
+            whole.mass_center.map(f64::to_bits),
+            merged.mass_center.map(f64::to_bits)
+        );
+        for b in &bodies {
+            let [a, c] = [&whole, &merged].map(|t| t.calculate_force(b, 0.5, None));
+            assert_eq!(a.map(f64::to_bits), c.map(f64::to_bits));
+        }
+        // including the order of the bodies in the bucket
+        let [whole, merged] = [whole, merged].map(|tree| {
+            let mut bytes = Vec::new();
+            serialize_tree_into(&tree, TreeFormat::Bitcode, &mut bytes);
+            bytes
+        });
+        assert_eq!(whole, merged);
+    }
+
+    #[test]
+    fn morton_sort_does_not_change_merged_tree() {
+        let bodies = random_bodies(300, 2);
+        let bounds = [[-4f64, 4f64], [-4f64, 4f64]];
+        let merged = |sort: bool| {
+            let mut root = tree_of(&[]);
+            for part in bodies.chunks(75) {
+                let mut part = part.to_vec();
+                if sort {
+                    sort_by_morton(&mut part, &bounds);
+                }
+                root.merge(tree_of(&part), 0f64);
+            }
+            // without it, the mass centers only match up to rounding
+            root.recompute_mass_centers();
+            let mut bytes = Vec::new();
+            serialize_tree_into(&root, TreeFormat::Bitcode, &mut bytes);
+            bytes
         };
+        assert_eq!(merged(true), merged(false));
+    }
+
+    #[test]
+    fn field_at_body_matches_its_force() {
+        let bodies = random_bodies(100, 3);
+        let tree = tree_of(&bodies);
+        for (i, b) in bodies.iter().enumerate().step_by(10) {
+            // a probe is never part of the tree, so the body is left out of it
+            let others: Vec<Body> = [&bodies[..i], &bodies[i + 1..]].concat();
+            let field = tree_of(&others).force_at(&b.position, 0f64, None);
+            let force = tree.calculate_force(b, 0f64, None);
+            for dim in 0..2 {
+                let expected = field[dim] * b.mass;
+                assert!(
+                    (force[dim] - expected).abs() <= 1e-12 * expected.abs(),
+                    "{:?} vs {:?}",
+                    force,
+                    field
+                );
+            }
+        }
+    }
+
+    /// Number of pairwise interactions of the force calculation at a position, the
+    /// bodies and nodes whose force is summed up.
+    fn interactions(
+        node: &TreeNode,
+        position: &[f64; 2],
+        theta: f64,
+        reference: Option<f64>,
+    ) -> usize {
+        if !node.bucket.is_empty() {
+            return 1 + node.bucket.len();
+        }
+        let distance = (node.mass_center[0] - position[0]).hypot(node.mass_center[1] - position[1]);
+        if node.body.is_some() {
+            1
+        } else if node.children.is_empty() || distance < 1e-10 {
+            0
+        } else if node.size / distance < node.opening_theta(theta, reference) {
+            1
+        } else {
+            node.children
+                .iter()
+                .map(|c| interactions(c, position, theta, reference))
+                .sum()
+        }
+    }
+
+    /// A dense core of 200 bodies within 0.1 of the origin in a sparse halo of 800 bodies.
+    fn cored_bodies() -> Vec<Body> {
+        let mut rng = StdRng::seed_from_u64(9);
+        (0..1000)
+            .map(|id| {
+                let extent = if id < 200 { 0.1 } else { 4f64 };
+                let position = [
+                    rng.gen_range(-extent..extent),
+                    rng.gen_range(-extent..extent),
+                ];
+                body(id, 1e3, position)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn adaptive_theta_resolves_the_core_better_at_lower_cost() {
+        let bodies = cored_bodies();
+        let tree = tree_of(&bodies);
+        let reference = Some(tree.mass / (tree.size * tree.size));
+        let core = &bodies[..200];
+        let core_error = |force: &dyn Fn(&Body) -> [f64; 2]| {
+            let squared_sum: f64 = core
+                .iter()
+                .map(|b| {
+                    let (f, exact) = (force(b), tree.calculate_force(b, 0f64, None));
+                    ((f[0] - exact[0]).hypot(f[1] - exact[1]) / exact[0].hypot(exact[1])).powi(2)
+                })
+                .sum();
+            (squared_sum / core.len() as f64).sqrt()
+        };
+        let cost = |theta: f64, reference: Option<f64>| -> usize {
+            bodies
+                .iter()
+                .map(|b| interactions(&tree, &b.position, theta, reference))
+                .sum()
+        };
+
+        let fixed = core_error(&|b| tree.calculate_force(b, 0.5, None));
+        let adaptive = core_error(&|b| tree.calculate_force_adaptive(b, 0.8, None));
+        assert!(cost(0.8, reference) < cost(0.5, None));
+        assert!(adaptive < 0.1 * fixed, "{} {}", adaptive, fixed);
+    }
+
+    #[test]
+    fn spline_softening_is_newtonian_beyond_its_length() {
+        let spline = Softening {
+            kernel: SofteningKernel::Spline,
+            length: 2f64,
+        };
+        for distance in [2f64, 2.5, 10f64] {
+            let newtonian = pair_force(3f64, &[distance, 0f64], distance, None);
+            let softened = pair_force(3f64, &[distance, 0f64], distance, Some(&spline));
+            assert!(
+                (softened[0] / newtonian[0] - 1f64).abs() < 1e-9,
+                "{}",
+                distance
+            );
+            let ratio = pair_potential(3f64, distance, Some(&spline))
+                / pair_potential(3f64, distance, None);
+            assert!((ratio - 1f64).abs() < 1e-9, "{}", distance);
+        }
+
+        // within the length, the force is weaker than Newtonian, continuous where the
+        // pieces of the kernel meet and vanishes at zero separation
+        for distance in [0.2, 1f64, 1.9] {
+            let newtonian = pair_force(3f64, &[distance, 0f64], distance, None);
+            let softened = pair_force(3f64, &[distance, 0f64], distance, Some(&spline));
+            assert!(
+                softened[0] > 0f64 && softened[0] < newtonian[0],
+                "{}",
+                distance
+            );
+        }
+        for joint in [1f64, 2f64] {
+            let factor = |distance: f64| spline.force_factor(distance);
+            assert!((factor(joint - 1e-9) / factor(joint + 1e-9) - 1f64).abs() < 1e-6);
+        }
+        assert_eq!(pair_force(3f64, &[0f64; 2], 0f64, Some(&spline)), [0f64; 2]);
+        assert!(pair_potential(3f64, 0f64, Some(&spline)).is_finite());
+    }
+
+    #[test]
+    fn pooled_trees_give_identical_forces_and_bounded_pool() {
+        let mut pool = NodePool::default();
+        let mut pool_sizes = Vec::new();
+        for step in 0..6 {
+            let bodies = random_bodies(300, step);
+            let mut pooled = TreeNode {
+                size: 8f64,
+                ..TreeNode::default()
+            };
+            for b in &bodies {
+                pooled.insert_pooled(b, &mut pool, 0f64);
+            }
+            let fresh = tree_of(&bodies);
+            for b in &bodies {
+                assert_eq!(
+                    pooled.calculate_force(b, 0.5, None),
+                    fresh.calculate_force(b, 0.5, None)
+                );
+            }
+
+            // like a tree merged with those of other processes, larger than the built one
+            let mut merged = pooled.clone();
+            for b in &random_bodies(1000, 100 + step) {
+                merged.insert(b);
+            }
+            pool.reclaim(&mut merged);
+            assert_eq!(merged.children.capacity(), 0);
+            pool_sizes.push(pool.free.len());
+        }
+        assert!(pool.peak > 0);
+        assert!(
+            pool_sizes.iter().all(|&n| n <= pool.peak),
+            "{:?}",
+            pool_sizes
+        );
+    }
+
+    #[test]
+    fn merging_keeps_the_depth_cap_of_nearly_coincident_bodies() {
+        fn depth(node: &TreeNode) -> usize {
+            1 + node.children.iter().map(depth).max().unwrap_or(0)
+        }
+        // 1000 bodies within 1e-13 of each other, spread over two processes' trees
+        let mut rng = StdRng::seed_from_u64(10);
+        let bodies: Vec<Body> = (0..1000)
+            .map(|id| {
+                let offset = [rng.gen_range(0f64..1e-13), rng.gen_range(0f64..1e-13)];
+                body(id, 1f64, [1f64 + offset[0], 1f64 + offset[1]])
+            })
+            .collect();
+        let min_size = 8f64 / 2f64.powi(SAFE_MODE_MAX_DEPTH);
+        let build = |part: &[Body]| {
+            let mut root = TreeNode {
+                size: 8f64,
+                ..TreeNode::default()
+            };
+            for b in part {
+                root.insert_pooled(b, &mut NodePool::default(), min_size);
+            }
+            root
+        };
+        let (first, second) = bodies.split_at(500);
+
+        let mut capped = build(first);
+        capped.merge(build(second), min_size);
+        let mut unlimited = build(first);
+        unlimited.merge(build(second), 0f64);
+        assert!(depth(&capped) <= SAFE_MODE_MAX_DEPTH as usize + 1);
+        assert!(depth(&unlimited) > depth(&capped));
+
+        assert_eq!(capped.mass, 1000f64);
+        let far = body(1000, 1f64, [-3f64, -3f64]);
+        for b in bodies.iter().chain([&far]) {
+            let force = capped.calculate_force(b, 0.5, None);
+            assert!(force.iter().all(|f| f.is_finite()), "{}: {:?}", b.id, force);
+        }
+    }
+
+    #[test]
+    fn coarsened_trees_trade_force_accuracy_for_size() {
+        // four processes, each holding the bodies of one quadrant of the root
+        let bodies = random_bodies(2000, 12);
+        let quadrant =
+            |b: &Body| (b.position[0] > 0f64) as usize * 2 + (b.position[1] > 0f64) as usize;
+        let parts: Vec<Vec<Body>> = (0..4)
+            .map(|q| {
+                bodies
+                    .iter()
+                    .filter(|b| quadrant(b) == q)
+                    .cloned()
+                    .collect()
+            })
+            .collect();
+        let regions: Vec<[[f64; 2]; 2]> = parts
+            .iter()
+            .map(|part| {
+                let bound = |dim: usize| {
+                    let values = part.iter().map(|b| b.position[dim]);
+                    [
+                        values.clone().fold(f64::INFINITY, f64::min),
+                        values.fold(f64::NEG_INFINITY, f64::max),
+                    ]
+                };
+                [bound(0), bound(1)]
+            })
+            .collect();
+        let trees: Vec<TreeNode> = parts.iter().map(|part| tree_of(part)).collect();
+        let exact = tree_of(&bodies);
+
+        // relative force error of the bodies of process 0 and the bytes it receives
+        let receive = |merge_theta: f64| {
+            let mut root = trees[0].clone();
+            let mut bytes = 0;
+            for (sender, tree) in trees.iter().enumerate().skip(1) {
+                let others: Vec<_> = (0..4)
+                    .filter(|&r| r != sender)
+                    .map(|r| regions[r])
+                    .collect();
+                let mut buf = Vec::new();
+                serialize_coarsened_into(
+                    tree.coarsened(merge_theta, &others),
+                    TreeFormat::Compact,
+                    &mut buf,
+                );
+                bytes += buf.len();
+                root.merge(deserialize_tree(&buf, TreeFormat::Compact).unwrap(), 0f64);
+            }
+            let error: f64 = parts[0]
+                .iter()
+                .map(|b| {
+                    let (f, e) = (
+                        root.calculate_force(b, 0.5, None),
+                        exact.calculate_force(b, 0.5, None),
+                    );
+                    (f[0] - e[0]).hypot(f[1] - e[1]) / e[0].hypot(e[1])
+                })
+                .sum::<f64>()
+                / parts[0].len() as f64;
+            (error, bytes)
+        };
+
+        // replaced nodes are those the receivers would not open under the force theta of
+        // 0.5 anyway, a larger merge theta saves more bytes but changes the forces
+        let results = [0f64, 0.25, 0.5, 1f64].map(receive);
+        assert!(results.windows(2).all(|w| w[1].1 < w[0].1), "{:?}", results);
+        assert!(
+            results[..3].iter().all(|&(error, _)| error < 1e-12),
+            "{:?}",
+            results
+        );
+        let (error, bytes) = results[3];
+        assert!(error > 0f64 && error < 0.01, "{}", error);
+        assert!(bytes < results[0].1 / 4, "{:?}", results);
     }
 }
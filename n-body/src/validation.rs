@@ -0,0 +1,323 @@
+//! Checks of the initial conditions for problems that would spoil a run.
+
+use super::diagnostics::center_of_mass;
+use super::{get_bounds, Args, Body, G};
+
+use std::collections::HashSet;
+
+/// Bodies faster than this multiple of the median speed count as outliers.
+const OUTLIER_FACTOR: f64 = 10f64;
+
+/// A grid cell holding this many times the average number of bodies is densely packed.
+const DENSE_FACTOR: f64 = 10f64;
+
+/// Largest sensible ratio of the timestep to the shortest dynamical time.
+const MAX_STEP_RATIO: f64 = 0.1;
+
+/// Fraction of bodies faster than the escape velocity from which on a warning is printed.
+const ESCAPE_FRACTION: f64 = 0.5;
+
+/// Numbers of bodies showing each pathology.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Pathologies {
+    /// Bodies at exactly the position of another body.
+    pub(crate) coincident: usize,
+    /// Bodies with a negative mass.
+    pub(crate) negative_mass: usize,
+    /// Bodies with a NaN or infinite mass, position, velocity or radius.
+    pub(crate) non_finite: usize,
+    /// Speed outliers which are faster than the escape speed of the system.
+    pub(crate) escaping: usize,
+}
+
+impl Pathologies {
+    pub(crate) fn total(&self) -> usize {
+        self.coincident + self.negative_mass + self.non_finite + self.escaping
+    }
+}
+
+/// Scan the bodies for pathologies.
+///
+/// A body is an escaping outlier if its speed relative to the center of mass exceeds
+/// `OUTLIER_FACTOR` times the median speed as well as the escape speed `sqrt(2 G M / r)`
+/// of the whole system at its distance `r`.
+///
+/// * `bodies`: Bodies to check.
+pub(crate) fn find_pathologies(bodies: &[Body]) -> Pathologies {
+    let mut pathologies = Pathologies::default();
+
+    let is_finite = |b: &Body| {
+        [b.mass, b.radius]
+            .iter()
+            .chain(&b.position)
+            .chain(&b.velocity)
+            .all(|v| v.is_finite())
+    };
+    let (finite, non_finite): (Vec<&Body>, Vec<&Body>) = bodies.iter().partition(|b| is_finite(b));
+    pathologies.non_finite = non_finite.len();
+    pathologies.negative_mass = bodies.iter().filter(|b| b.mass < 0f64).count();
+
+    let mut positions = HashSet::new();
+    for b in finite.iter() {
+        // adding zero maps -0.0 to 0.0, so both count as the same position
+        let key = (
+            (b.position[0] + 0f64).to_bits(),
+            (b.position[1] + 0f64).to_bits(),
+        );
+        if !positions.insert(key) {
+            pathologies.coincident += 1;
+        }
+    }
+
+    let finite: Vec<Body> = finite.into_iter().cloned().collect();
+    let total_mass: f64 = finite.iter().map(|b| b.mass).sum();
+    if let Some((com_position, com_velocity)) = center_of_mass(&finite) {
+        let speeds: Vec<f64> = finite
+            .iter()
+            .map(|b| {
+                f64::hypot(
+                    b.velocity[0] - com_velocity[0],
+                    b.velocity[1] - com_velocity[1],
+                )
+            })
+            .collect();
+        let mut sorted = speeds.clone();
+        sorted.sort_by(f64::total_cmp);
+        let median = sorted[sorted.len() / 2];
+
+        pathologies.escaping = finite
+            .iter()
+            .zip(speeds)
+            .filter(|(b, speed)| {
+                let distance = f64::hypot(
+                    b.position[0] - com_position[0],
+                    b.position[1] - com_position[1],
+                );
+                *speed > OUTLIER_FACTOR * median
+                    && distance > 0f64
+                    && *speed > (2f64 * G * total_mass / distance).sqrt()
+            })
+            .count();
+    }
+
+    pathologies
+}
+
+/// Heuristic warnings about parameters which are likely to produce blowups or NaNs.
+///
+/// The densest region is estimated by binning the bodies into a grid of about one cell
+/// per body. Its dynamical time is the free-fall time `sqrt(d^3 / (G M))` of a cell of
+/// size `d` holding the mass `M`.
+///
+/// With `--warn-on-escape-velocity`, the escape velocity of the system is estimated as
+/// `sqrt(2 G M / R)` with the total mass `M` and half the extent of the bounds `R`.
+///
+/// * `args`: Arguments of the run.
+/// * `bodies`: Initial bodies, without padding.
+/// * `time_scale`: Factor converting the step time into seconds.
+pub(crate) fn check_parameters(args: &Args, bodies: &[Body], time_scale: f64) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    if args.theta > 1f64 {
+        warnings.push(format!(
+            "theta {} is larger than 1, distant nodes are approximated very coarsely",
+            args.theta
+        ));
+    }
+
+    let positions: Vec<[f64; 2]> = bodies.iter().map(|b| b.position).collect();
+    let Ok(bounds) = get_bounds(&positions) else {
+        // non-finite positions are reported by --validate-input
+        return warnings;
+    };
+    if args.warn_on_escape_velocity {
+        warnings.extend(check_escape_velocity(bodies, &bounds));
+    }
+
+    let cell_size = f64::max(bounds[0][1] - bounds[0][0], bounds[1][1] - bounds[1][0])
+        / (bodies.len() as f64).sqrt().ceil();
+    if bodies.len() < 2 || !(cell_size > 0f64 && cell_size.is_finite()) {
+        return warnings;
+    }
+
+    let mut cells = std::collections::HashMap::new();
+    for b in bodies {
+        let cell = (
+            ((b.position[0] - bounds[0][0]) / cell_size) as u64,
+            ((b.position[1] - bounds[1][0]) / cell_size) as u64,
+        );
+        let (count, mass) = cells.entry(cell).or_insert((0usize, 0f64));
+        *count += 1;
+        *mass += b.mass;
+    }
+
+    let (densest_count, _) = cells.values().max_by_key(|(count, _)| *count).unwrap();
+    let average_count = bodies.len() as f64 / cells.len() as f64;
+    if args.softening.is_none() && *densest_count as f64 > DENSE_FACTOR * average_count {
+        warnings.push(format!(
+            "{} bodies are packed into a cell of size {} and forces are not softened, \
+             close encounters may blow up",
+            densest_count, cell_size
+        ));
+    }
+
+    let heaviest_mass = cells.values().map(|(_, mass)| *mass).fold(0f64, f64::max);
+    if heaviest_mass > 0f64 {
+        let dynamical_time = (cell_size.powi(3) / (G * heaviest_mass)).sqrt();
+        let timestep = (args.step_time * time_scale).abs();
+        if timestep > MAX_STEP_RATIO * dynamical_time {
+            warnings.push(format!(
+                "the timestep of {} s is large compared to the dynamical time of {} s \
+                 of the densest region",
+                timestep, dynamical_time
+            ));
+        }
+    }
+
+    warnings
+}
+
+/// Warning if a large fraction of the bodies starts faster than the escape velocity.
+///
+/// * `bodies`: Initial bodies, without padding.
+/// * `bounds`: Bounds of the bodies.
+fn check_escape_velocity(bodies: &[Body], bounds: &[[f64; 2]; 2]) -> Option<String> {
+    let (_, com_velocity) = center_of_mass(bodies)?;
+    let total_mass: f64 = bodies.iter().map(|b| b.mass).sum();
+    let radius = f64::max(bounds[0][1] - bounds[0][0], bounds[1][1] - bounds[1][0]) / 2f64;
+    if radius <= 0f64 {
+        return None;
+    }
+
+    let escape_velocity = (2f64 * G * total_mass / radius).sqrt();
+    let n_escaping = bodies
+        .iter()
+        .filter(|b| {
+            f64::hypot(
+                b.velocity[0] - com_velocity[0],
+                b.velocity[1] - com_velocity[1],
+            ) > escape_velocity
+        })
+        .count();
+
+    let fraction = n_escaping as f64 / bodies.len() as f64;
+    (fraction >= ESCAPE_FRACTION).then(|| {
+        format!(
+            "{} of {} bodies start faster than the escape velocity {} of the system and will \
+             fly apart, consider a velocity_max (-S) below it",
+            n_escaping,
+            bodies.len(),
+            escape_velocity
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn body(id: usize, mass: f64, position: [f64; 2], velocity: [f64; 2]) -> Body {
+        Body {
+            id,
+            mass,
+            position,
+            velocity,
+            ..Body::default()
+        }
+    }
+
+    #[test]
+    fn crafted_bad_input_reports_pathologies() {
+        let mut bodies: Vec<Body> = (0..20)
+            .map(|id| body(id, 1f64, [id as f64, (id % 3) as f64], [0.1, 0f64]))
+            .collect();
+        // two bodies on top of others
+        bodies.push(body(20, 1f64, [0f64, 0f64], [0f64, 0f64]));
+        bodies.push(body(21, 1f64, [-0f64, 0f64], [0f64, 0f64]));
+        bodies.push(body(22, -1f64, [30f64, 0f64], [0f64, 0f64]));
+        bodies.push(body(23, 1f64, [f64::NAN, 0f64], [0f64, 0f64]));
+        bodies.push(body(24, 1f64, [40f64, 0f64], [0f64, f64::INFINITY]));
+        // far faster than the others and the escape speed of a few kilograms
+        bodies.push(body(25, 1f64, [50f64, 0f64], [1e3, 0f64]));
+
+        let pathologies = find_pathologies(&bodies);
+        assert_eq!(
+            pathologies,
+            Pathologies {
+                coincident: 2,
+                negative_mass: 1,
+                non_finite: 2,
+                escaping: 1,
+            }
+        );
+        assert_eq!(pathologies.total(), 6);
+    }
+
+    #[test]
+    fn clean_input_has_no_pathologies() {
+        let bodies: Vec<Body> = (0..20)
+            .map(|id| body(id, 1f64, [id as f64, 0f64], [0f64, id as f64 * 0.01]))
+            .collect();
+        assert_eq!(find_pathologies(&bodies).total(), 0);
+    }
+
+    #[test]
+    fn bad_parameters_are_warned_about() {
+        // a 10 by 10 grid of spacing 10 with 30 heavy bodies packed into its corner cell
+        let mut bodies: Vec<Body> = (0..100)
+            .map(|id| {
+                body(
+                    id,
+                    1f64,
+                    [(id % 10) as f64 * 10f64, (id / 10) as f64 * 10f64],
+                    [0f64; 2],
+                )
+            })
+            .collect();
+        bodies.extend((100..130).map(|id| {
+            let offset = (id - 100) as f64 * 0.01;
+            body(id, 1e10, [1f64 + offset, 1f64 - offset], [0f64; 2])
+        }));
+
+        let args = Args::parse_from(["n-body", "-t", "1.5", "-l", "10"]);
+        let warnings = check_parameters(&args, &bodies, 1f64);
+        assert_eq!(warnings.len(), 3, "{:?}", warnings);
+        assert!(warnings[0].starts_with("theta 1.5 is larger than 1"));
+        assert!(warnings[1].starts_with("31 bodies are packed"));
+        assert!(warnings[2].starts_with("the timestep of 10 s is large"));
+
+        // softened forces regularize the close encounters of the packed bodies
+        let args = Args::parse_from(["n-body", "-t", "0.5", "-l", "0.01", "--softening", "1"]);
+        assert_eq!(check_parameters(&args, &bodies, 1f64), Vec::<String>::new());
+
+        let args = Args::parse_from(["n-body", "-t", "0.5", "-l", "0.01"]);
+        assert_eq!(
+            check_parameters(&args, &bodies[..100], 1f64),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn high_velocity_max_warns_about_escaping_bodies() {
+        let escape_warnings = |velocity_max: &str| {
+            let args = Args::parse_from([
+                "n-body",
+                "-n",
+                "200",
+                "-S",
+                velocity_max,
+                "--warn-on-escape-velocity",
+            ]);
+            let bodies = crate::generate_bodies(&mut StdRng::seed_from_u64(2), &args);
+            check_parameters(&args, &bodies, 1f64)
+                .into_iter()
+                .filter(|w| w.contains("escape velocity"))
+                .count()
+        };
+        assert_eq!(escape_warnings("1e6"), 1);
+        assert_eq!(escape_warnings("0"), 0);
+    }
+}